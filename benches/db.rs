@@ -0,0 +1,54 @@
+//! Lookups and broadcasts over a large user base, through the same
+//! `ClientDB` calls the reactor makes.
+//!
+//! cargo bench --bench db
+
+use std::time::{Duration, Instant};
+
+use pi_server::{
+    client::CliTask,
+    db::{CliData, ClientDB},
+    storage::{MemoryStorage, Storage},
+};
+use serde_json::json;
+use uuid::Uuid;
+
+const USERS: usize = 10_000;
+const ROUNDS: u32 = 100;
+
+fn user(i: usize) -> CliData {
+    serde_json::from_value(json!({
+        "addr": "127.0.0.1:31337",
+        "uid": Uuid::new_v4(),
+        "jobs": [],
+        "login": format!("user{}", i),
+        "password": null,
+        "online": false,
+    }))
+    .unwrap()
+}
+
+fn report(what: &str, per_op: Duration) {
+    eprintln!("{} users, {:<16}{:>12?}/iter", USERS, what, per_op);
+}
+
+fn main() {
+    let storage = MemoryStorage::default();
+    storage
+        .save(&(0..USERS).map(user).collect::<Vec<_>>())
+        .unwrap();
+    ClientDB::load(Box::new(storage)).unwrap();
+
+    let start = Instant::now();
+    for i in 0..USERS {
+        assert!(ClientDB::get_client_by_username(&format!("user{}", i)).is_some());
+    }
+    report("lookup by login", start.elapsed() / USERS as u32);
+
+    let task = CliTask::SendMsg("now".into(), "bench".into(), "hello".into());
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        ClientDB::add_broadcast_task(task.clone()).unwrap();
+    }
+    report("broadcast", start.elapsed() / ROUNDS);
+}
@@ -62,7 +62,9 @@ int sock_init() {
 
 
 int send_buf(int sock_fd, char* buf) {
-	return errwrap(send(sock_fd, buf, strlen(buf), 0));
+	// каждая команда должна заканчиваться переводом строки
+	errwrap(send(sock_fd, buf, strlen(buf), 0));
+	return errwrap(send(sock_fd, "\n", 1, 0));
 }
 
 int handle_ECHO(int sock_fd) {
@@ -110,7 +110,9 @@ void set_input_mode (void)
 
 
 int send_buf(int sock_fd, char* buf) {
-	return errwrap(send(sock_fd, buf, strlen(buf), 0));
+	// каждая команда должна заканчиваться переводом строки
+	errwrap(send(sock_fd, buf, strlen(buf), 0));
+	return errwrap(send(sock_fd, "\n", 1, 0));
 }
 
 
@@ -1,24 +1,57 @@
-use crate::{client::CliTask, config::*, db::ClientDB, error::SError};
+use crate::{
+    auth::Role,
+    client::{CliTask, Session},
+    config::{self, *},
+    db::{Ban, ClientDB},
+    error::SError,
+    history::{HistoryQuery, Message},
+    protocol::escape,
+    ratelimit::{self, Payer},
+    server,
+};
 use chrono::prelude::*;
 use regex::Regex;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::thread;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use uuid::Uuid;
 
 pub type RResult<T> = std::result::Result<T, SError>;
 pub type HResult = RResult<HandleResult>;
-pub type Args<'s> = HashMap<&'s str, &'s str>;
+/// Names are borrowed from the request line unless they had to be unescaped
+pub type Args<'s> = HashMap<Cow<'s, str>, String>;
 type Handler = fn(HandleInfo) -> HResult;
 
 #[derive(PartialEq, Debug)]
 pub struct Command<'cmd> {
-    pub cmd: &'cmd str,
+    pub cmd: Cow<'cmd, str>,
     pub args: Args<'cmd>,
 }
 
+/// Args whose values never go to the log
+const SECRET_ARGS: &[&str] = &["password"];
+
+/// A text request line for the log, with the secrets hidden
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.cmd)?;
+        let mut args = self.args.iter().collect::<Vec<_>>();
+        args.sort();
+        for (k, v) in args {
+            let v = if SECRET_ARGS.contains(&k.as_ref()) {
+                "***".to_string()
+            } else {
+                escape(v)
+            };
+            write!(f, "|{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct HandleResult(pub String);
 
 impl From<String> for HandleResult {
@@ -43,36 +76,86 @@ pub struct HandleInfo<'cmd> {
     pub args: Args<'cmd>,
     pub addr: &'cmd SocketAddr,
     pub uid: Uuid,
+    pub session: &'cmd mut Session,
 }
 
 lazy_static! {
-    static ref RULES: HashMap<&'static str, (Vec<&'static str>, Handler)> = {
+    // command -> (required args, minimal role, handler)
+    static ref RULES: HashMap<&'static str, (Vec<&'static str>, Role, Handler)> = {
         let mut rules = HashMap::new();
-        rules.insert("HELP", (vec![], API::get_help as Handler));
-        rules.insert("PING", (vec![], API::ping as Handler));
-        rules.insert("ECHO", (vec!["msg"], API::echo as Handler));
-        rules.insert("USERS", (vec![], API::get_users as Handler));
+        rules.insert("HELP", (vec![], Role::User, API::get_help as Handler));
+        rules.insert("PING", (vec![], Role::User, API::ping as Handler));
+        rules.insert("ECHO", (vec!["msg"], Role::User, API::echo as Handler));
+        rules.insert("USERS", (vec![], Role::User, API::get_users as Handler));
         rules.insert(
             "LOGIN",
-            (vec!["username", "password"], API::login as Handler),
+            (vec!["username", "password"], Role::User, API::login as Handler),
         );
-        rules.insert("SEND", (vec!["username", "msg"], API::send_to as Handler));
-        rules.insert("SNDALL", (vec!["msg"], API::send_to_all as Handler));
-        rules.insert("EXIT", (vec![], API::cli_exit as Handler));
-        rules.insert("_DELUSER", (vec!["username"], API::del_user as Handler));
-        rules.insert("_FLUSH", (vec!["username"], API::flush_jobs as Handler));
+        rules.insert("SEND", (vec!["username", "msg"], Role::User, API::send_to as Handler));
+        rules.insert("SNDALL", (vec!["msg"], Role::User, API::send_to_all as Handler));
+        rules.insert("EXIT", (vec![], Role::User, API::cli_exit as Handler));
+        rules.insert("JOIN", (vec!["room"], Role::User, API::join_room as Handler));
+        rules.insert("PART", (vec!["room"], Role::User, API::part_room as Handler));
+        rules.insert("ROOMS", (vec![], Role::User, API::get_rooms as Handler));
+        rules.insert("MEMBERS", (vec!["room"], Role::User, API::get_members as Handler));
+        rules.insert(
+            "SENDROOM",
+            (vec!["room", "msg"], Role::User, API::send_to_room as Handler),
+        );
+        rules.insert("HISTORY", (vec![], Role::User, API::history as Handler));
+        rules.insert("PROTO", (vec!["mode"], Role::User, API::set_proto as Handler));
+        rules.insert("LANG", (vec!["lang"], Role::User, API::set_lang as Handler));
+        // revoke=1 drops the token instead
+        rules.insert("TOKEN", (vec![], Role::User, API::api_token as Handler));
+        rules.insert(
+            "KICK",
+            (vec!["username"], Role::Moderator, API::kick as Handler),
+        );
+        rules.insert(
+            "MUTE",
+            (vec!["username", "minutes"], Role::Moderator, API::mute as Handler),
+        );
+        // by username or by addr
+        rules.insert("BAN", (vec!["reason"], Role::Moderator, API::ban as Handler));
+        rules.insert("UNBAN", (vec![], Role::Moderator, API::unban as Handler));
+        rules.insert(
+            "_DELUSER",
+            (vec!["username"], Role::Admin, API::del_user as Handler),
+        );
+        rules.insert(
+            "_FLUSH",
+            (vec!["username"], Role::Admin, API::flush_jobs as Handler),
+        );
+        rules.insert("_QUEUES", (vec![], Role::Admin, API::queues as Handler));
+        rules.insert(
+            "_GRANT",
+            (vec!["username", "role"], Role::Admin, API::grant_role as Handler),
+        );
+        rules.insert(
+            "_REVOKE",
+            (vec!["username"], Role::Admin, API::revoke_role as Handler),
+        );
+        // optional seconds and reason
+        rules.insert("_SHUTDOWN", (vec![], Role::Admin, API::shutdown as Handler));
+        rules.insert("_RELOAD", (vec![], Role::Admin, API::reload as Handler));
         rules
     };
-    static ref LOGIN_RULE: Regex = Regex::new(r"^[\x20-\x39\x3B-\x7Eа-яёА-ЯЁ]{1,20}$").unwrap();
+    pub static ref LOGIN_RULE: Regex =
+        Regex::new(r"^[\x20-\x39\x3B-\x7Eа-яёА-ЯЁ]{1,20}$").unwrap();
+    static ref ROOM_RULE: Regex = Regex::new(r"^[A-Za-z0-9_-]{1,32}$").unwrap();
 }
 
 pub struct API;
 
 impl API {
-    fn _help() -> String {
+    /// Commands available to `role`, `_`-prefixed ones only for staff
+    fn _help(role: Role) -> String {
         let mut cmds = RULES
-            .keys()
-            .filter_map(|k| if k.starts_with('_') { None } else { Some(*k) })
+            .iter()
+            .filter(|(k, (_, min_role, _))| {
+                *min_role <= role && (role > Role::User || !k.starts_with('_'))
+            })
+            .map(|(k, _)| *k)
             .collect::<Vec<&str>>();
         cmds.sort();
         format!(
@@ -82,18 +165,28 @@ impl API {
         )
     }
 
-    fn check_admin(uid: Uuid) -> RResult<()> {
-        let caller = match ClientDB::get_username(uid) {
-            Some(c) => c,
-            None => return Err(SError::NoSuchUser),
-        };
-        if caller == ADMIN {
-            Ok(())
-        } else {
-            Err(SError::UnknownCommand)
+    /// Commands for users only don't need a login, the others do
+    fn check_role(uid: Uuid, min_role: Role) -> RResult<()> {
+        if min_role == Role::User {
+            return Ok(());
+        }
+        match ClientDB::get_role(uid) {
+            None => Err(SError::NotLoggedIn),
+            Some(role) if role < min_role => Err(SError::PermissionDenied(min_role)),
+            Some(_) => Ok(()),
         }
     }
 
+    /// Room name from the args, with an optional leading `#`
+    fn room_arg(h: &HandleInfo) -> RResult<String> {
+        let room = h.args.get("room").unwrap();
+        let room = room.strip_prefix(ROOM_MARK).unwrap_or(room);
+        if !ROOM_RULE.is_match(room) {
+            return Err(SError::InvalidRoom);
+        }
+        Ok(room.to_string())
+    }
+
     fn check_login(uid: Uuid) -> RResult<()> {
         if !ClientDB::is_logged_in(uid) {
             Err(SError::NotLoggedIn)
@@ -103,7 +196,6 @@ impl API {
     }
 
     pub fn flush_jobs(h: HandleInfo) -> HResult {
-        Self::check_login(h.uid).and(Self::check_admin(h.uid))?;
         let user = h.args.get("username").unwrap().to_string();
         let uid = match ClientDB::get_client_by_username(&user) {
             Some(r) => r,
@@ -116,8 +208,39 @@ impl API {
         Ok(jobs_cnt.to_string().into())
     }
 
+    /// Undelivered messages per user, the longest queues first
+    pub fn queues(_: HandleInfo) -> HResult {
+        let mut sizes = ClientDB::get_queue_sizes();
+        sizes.sort_by(|(a, a_len), (b, b_len)| b_len.cmp(a_len).then(a.cmp(b)));
+        let sizes = sizes
+            .iter()
+            .map(|(user, len)| format!("{}: {}", user, len))
+            .collect::<Vec<String>>();
+        Ok(sizes.join("\n").into())
+    }
+
+    pub fn shutdown(h: HandleInfo) -> HResult {
+        let seconds = match h.args.get("seconds").map(|s| s.parse::<u64>()) {
+            Some(Ok(s)) if s <= MAX_SHUTDOWN_SECONDS => s,
+            Some(_) => {
+                let range = format!("seconds must be 0..{}", MAX_SHUTDOWN_SECONDS);
+                return Err(SError::InvalidArg(range));
+            }
+            None => 0,
+        };
+        let reason = h.args.get("reason").filter(|r| !r.is_empty()).cloned();
+        info!("{} is stopping the server in {} s", Self::moderator(&h), seconds);
+        server::stop(Duration::from_secs(seconds), reason);
+        Ok(().into())
+    }
+
+    pub fn reload(h: HandleInfo) -> HResult {
+        info!("{} is reloading the config", Self::moderator(&h));
+        let changes = reload()?;
+        Ok(changes.join("\n").into())
+    }
+
     pub fn del_user(h: HandleInfo) -> HResult {
-        Self::check_login(h.uid).and(Self::check_admin(h.uid))?;
         let user = h.args.get("username").unwrap().to_string();
         let uid = match ClientDB::get_client_by_username(&user) {
             Some(r) => r,
@@ -126,9 +249,105 @@ impl API {
                 None => return Err(SError::NoSuchUser),
             },
         };
-        ClientDB::add_task(uid, CliTask::Exit);
-        thread::sleep(Duration::from_secs(1));
+        let login = ClientDB::get_username(uid);
+        server::disconnect(uid);
         ClientDB::remove_cli(uid);
+        if let Some(login) = login {
+            ClientDB::forget_history(&login);
+        }
+        Ok(().into())
+    }
+
+    /// Nothing if every queue took a message for many, or how many
+    /// didn't (only with `queue_overflow = "reject"`)
+    fn refused(n: usize) -> HandleResult {
+        match n {
+            0 => ().into(),
+            n => n.to_string().into(),
+        }
+    }
+
+    fn moderator(h: &HandleInfo) -> String {
+        ClientDB::get_username(h.uid).unwrap_or_else(|| h.addr.to_string())
+    }
+
+    fn ip_arg(h: &HandleInfo) -> RResult<Option<IpAddr>> {
+        match h.args.get("addr") {
+            Some(addr) => addr
+                .parse()
+                .map(Some)
+                .map_err(|_| SError::InvalidArg("addr must be an ip address".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn kick(h: HandleInfo) -> HResult {
+        let user = h.args.get("username").unwrap();
+        ClientDB::kick(h.uid, user)?;
+        let reason = h.args.get("reason").map(String::as_str).unwrap_or("-");
+        info!("{} kicked {}: {}", Self::moderator(&h), user, reason);
+        Ok(().into())
+    }
+
+    pub fn mute(h: HandleInfo) -> HResult {
+        let user = h.args.get("username").unwrap();
+        let minutes = match h.args.get("minutes").unwrap().parse::<u64>() {
+            Ok(m) if m <= MAX_MUTE_MINUTES => m,
+            _ => {
+                let range = format!("minutes must be 0..{}", MAX_MUTE_MINUTES);
+                return Err(SError::InvalidArg(range));
+            }
+        };
+        ClientDB::mute(h.uid, user, minutes)?;
+        info!("{} muted {} for {} min", Self::moderator(&h), user, minutes);
+        Ok(().into())
+    }
+
+    pub fn ban(h: HandleInfo) -> HResult {
+        let reason = h.args.get("reason").unwrap().to_string();
+        let moderator = Self::moderator(&h);
+        let ban = Ban::new(moderator.clone(), reason.clone());
+        match (h.args.get("username"), Self::ip_arg(&h)?) {
+            (Some(user), None) => {
+                ClientDB::ban(h.uid, user, ban)?;
+                info!("{} banned {}: {}", moderator, user, reason);
+            }
+            (None, Some(addr)) => {
+                ClientDB::ban_ip(addr, ban);
+                info!("{} banned address {}: {}", moderator, addr, reason);
+            }
+            _ => return Err(SError::WrongArgs("username or addr, reason".to_string())),
+        }
+        Ok(().into())
+    }
+
+    pub fn unban(h: HandleInfo) -> HResult {
+        match (h.args.get("username"), Self::ip_arg(&h)?) {
+            (Some(user), None) => {
+                ClientDB::unban(user)?;
+                info!("{} unbanned {}", Self::moderator(&h), user);
+            }
+            (None, Some(addr)) => {
+                ClientDB::unban_ip(addr)?;
+                info!("{} unbanned address {}", Self::moderator(&h), addr);
+            }
+            _ => return Err(SError::WrongArgs("username or addr".to_string())),
+        }
+        Ok(().into())
+    }
+
+    pub fn grant_role(h: HandleInfo) -> HResult {
+        let user = h.args.get("username").unwrap();
+        let role = h.args.get("role").unwrap().parse()?;
+        ClientDB::set_role(user, role)?;
+        info!("{} made {} {}", Self::moderator(&h), user, role);
+        Ok(().into())
+    }
+
+    pub fn revoke_role(h: HandleInfo) -> HResult {
+        let user = h.args.get("username").unwrap();
+        ClientDB::set_role(user, Role::User)?;
+        info!("{} made {} {}", Self::moderator(&h), user, Role::User);
         Ok(().into())
     }
 
@@ -138,11 +357,18 @@ impl API {
         if !LOGIN_RULE.is_match(&username) {
             return Err(SError::InvalidLogin);
         }
-        ClientDB::set_login(h.uid, h.addr, username, password).map(HandleResult::from)
+        // pushes end the sender with " (to all)" or " (in #room)", so new
+        // names can't end the same way
+        if username.ends_with(')') && ClientDB::get_client_by_username(&username).is_none() {
+            return Err(SError::InvalidLogin);
+        }
+        ClientDB::set_login(h.uid, h.addr, username, password)?;
+        Ok(settings().motd.clone().unwrap_or_default().into())
     }
 
-    pub fn get_help(_: HandleInfo) -> HResult {
-        Ok(API::_help().into())
+    pub fn get_help(h: HandleInfo) -> HResult {
+        let role = ClientDB::get_role(h.uid).unwrap_or_default();
+        Ok(API::_help(role).into())
     }
 
     pub fn cli_exit(h: HandleInfo) -> HResult {
@@ -171,21 +397,24 @@ impl API {
 
     pub fn send_to_all(h: HandleInfo) -> HResult {
         Self::check_login(h.uid)?;
+        ClientDB::check_muted(h.uid)?;
         let sender = match ClientDB::get_username(h.uid) {
             Some(s) => s,
             None => h.addr.to_string(),
         };
-        let sender = sender + " (to all)";
         let message = h.args.get("msg").unwrap().to_string();
         let date = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let task = CliTask::SendMsg(date, sender, message);
-        ClientDB::add_broadcast_task(h.uid, task).map(HandleResult::from)
+        let task = CliTask::Broadcast(date, sender.clone(), message.clone());
+        let refused = ClientDB::add_broadcast_task(task)?;
+        ClientDB::log_message(Message::new(sender, None, message));
+        Ok(Self::refused(refused))
     }
 
     pub fn send_to(h: HandleInfo) -> HResult {
         Self::check_login(h.uid)?;
-        let receiver = h.args.get("username").unwrap().to_string();
-        let receiver = match ClientDB::get_client_by_username(&receiver) {
+        ClientDB::check_muted(h.uid)?;
+        let receiver_name = h.args.get("username").unwrap().to_string();
+        let receiver = match ClientDB::get_client_by_username(&receiver_name) {
             Some(r) => r,
             None => return Err(SError::NoSuchUser),
         };
@@ -195,8 +424,106 @@ impl API {
         };
         let message = h.args.get("msg").unwrap().to_string();
         let date = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        let task = CliTask::SendMsg(date, sender, message);
-        ClientDB::add_task(receiver, task).map(HandleResult::from)
+        let task = CliTask::SendMsg(date, sender.clone(), message.clone());
+        ClientDB::add_task(receiver, task)?;
+        ClientDB::log_message(Message::new(sender, Some(receiver_name), message));
+        Ok(().into())
+    }
+
+    pub fn join_room(h: HandleInfo) -> HResult {
+        Self::check_login(h.uid)?;
+        let room = Self::room_arg(&h)?;
+        ClientDB::join_room(h.uid, &room).map(HandleResult::from)
+    }
+
+    pub fn part_room(h: HandleInfo) -> HResult {
+        Self::check_login(h.uid)?;
+        let room = Self::room_arg(&h)?;
+        ClientDB::part_room(h.uid, &room).map(HandleResult::from)
+    }
+
+    pub fn get_rooms(_: HandleInfo) -> HResult {
+        let mut rooms = ClientDB::get_rooms();
+        rooms.sort();
+        let rooms = rooms
+            .iter()
+            .map(|(room, members)| format!("{}{} ({})", ROOM_MARK, room, members))
+            .collect::<Vec<String>>();
+        Ok(rooms.join("\n").into())
+    }
+
+    pub fn get_members(h: HandleInfo) -> HResult {
+        let room = Self::room_arg(&h)?;
+        let mut members = ClientDB::get_room_members(&room)?;
+        members.sort();
+        Ok(members.join("\n").into())
+    }
+
+    pub fn send_to_room(h: HandleInfo) -> HResult {
+        Self::check_login(h.uid)?;
+        ClientDB::check_muted(h.uid)?;
+        let room = Self::room_arg(&h)?;
+        let sender = ClientDB::get_username(h.uid).ok_or(SError::NotLoggedIn)?;
+        let message = h.args.get("msg").unwrap().to_string();
+        let date = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let task = CliTask::RoomMsg(date, room.clone(), sender, message);
+        ClientDB::add_room_task(h.uid, &room, task).map(Self::refused)
+    }
+
+    pub fn history(h: HandleInfo) -> HResult {
+        Self::check_login(h.uid)?;
+        let user = ClientDB::get_username(h.uid).ok_or(SError::NotLoggedIn)?;
+        let with = h.args.get("with").cloned();
+        if let Some(with) = with.as_ref() {
+            if ClientDB::get_client_by_username(with).is_none() {
+                return Err(SError::NoSuchUser);
+            }
+        }
+        let before = match h.args.get("before") {
+            Some(b) => Some(
+                b.parse::<u64>()
+                    .map_err(|_| SError::InvalidArg("before".to_string()))?,
+            ),
+            None => None,
+        };
+        let limit = match h.args.get("limit").map(|l| l.parse::<usize>()) {
+            Some(Ok(l)) if (1..=HISTORY_MAX_LIMIT).contains(&l) => l,
+            Some(_) => {
+                let range = format!("limit must be 1..{}", HISTORY_MAX_LIMIT);
+                return Err(SError::InvalidArg(range));
+            }
+            None => HISTORY_LIMIT,
+        };
+        let query = HistoryQuery {
+            user,
+            with,
+            before,
+            limit,
+        };
+        let lines = ClientDB::history(&query)?
+            .iter()
+            .map(Message::to_line)
+            .collect::<Vec<String>>();
+        Ok(lines.join("\n").into())
+    }
+
+    pub fn set_proto(h: HandleInfo) -> HResult {
+        h.session.proto = h.args.get("mode").unwrap().parse()?;
+        Ok(().into())
+    }
+
+    pub fn set_lang(h: HandleInfo) -> HResult {
+        h.session.lang = h.args.get("lang").unwrap().parse()?;
+        Ok(().into())
+    }
+
+    pub fn api_token(h: HandleInfo) -> HResult {
+        Self::check_login(h.uid)?;
+        if h.args.contains_key("revoke") {
+            ClientDB::revoke_token(h.uid).map(HandleResult::from)
+        } else {
+            ClientDB::issue_token(h.uid).map(HandleResult::from)
+        }
     }
 
     pub fn ping(_: HandleInfo) -> HResult {
@@ -208,16 +535,62 @@ impl API {
     }
 }
 
-pub fn process_command(cmd: Command, uid: Uuid, addr: &SocketAddr) -> RResult<String> {
-    let (required_args, handler): &(Vec<&str>, Handler) =
-        match RULES.get(cmd.cmd.to_uppercase().trim()) {
+/// Apply the config file and the bans file again, on SIGHUP or `_RELOAD`.
+/// Returns what has changed, everything is logged.
+pub fn reload() -> RResult<Vec<String>> {
+    let mut changes = config::reload().map_err(|e| {
+        error!("Config not reloaded: {}", e);
+        SError::Reload(e.to_string())
+    })?;
+    match ClientDB::reload_ip_bans() {
+        Ok(n) => changes.push(format!("banned addresses: {}", n)),
+        Err(e) => error!("Can't reload banned addresses: {}", e),
+    }
+    for change in changes.iter() {
+        info!("Reloaded {}", change);
+    }
+    Ok(changes)
+}
+
+/// Moderation or admin command by its name, the `_` prefix is optional
+pub fn staff_command(name: &str) -> Option<&'static str> {
+    let name = name.to_uppercase();
+    [format!("_{}", name), name]
+        .iter()
+        .filter_map(|n| RULES.get_key_value(n.as_str()))
+        .find(|(_, (_, min_role, _))| *min_role > Role::User)
+        .map(|(cmd, _)| *cmd)
+}
+
+/// Whether there is a command with this (upper case) name
+pub fn is_command(name: &str) -> bool {
+    RULES.contains_key(name)
+}
+
+/// Whole milliseconds, so that retrying after them surely succeeds
+pub fn retry_after(wait: Duration) -> u64 {
+    wait.as_millis() as u64 + 1
+}
+
+pub fn process_command(
+    cmd: Command,
+    uid: Uuid,
+    addr: &SocketAddr,
+    session: &mut Session,
+) -> RResult<String> {
+    let (name, (required_args, min_role, handler)) =
+        match RULES.get_key_value(cmd.cmd.to_uppercase().trim()) {
             Some(m) => m,
             None => return Err(SError::UnknownCommand),
         };
-    ClientDB::check_cmd_timeout(uid)?;
-    let cmd_arg_names = cmd.args.keys().collect::<Vec<&&str>>();
+    let payer = match ClientDB::get_username(uid) {
+        Some(login) => Payer::User(login),
+        None => Payer::Conn(uid),
+    };
+    ratelimit::check_command(payer, name).map_err(|wait| SError::DOS(retry_after(wait)))?;
+    API::check_role(uid, *min_role)?;
     for argn in required_args.iter() {
-        if !cmd_arg_names.contains(&argn) {
+        if !cmd.args.contains_key(*argn) {
             return Err(SError::WrongArgs(required_args.join(", ")));
         }
     }
@@ -225,6 +598,261 @@ pub fn process_command(cmd: Command, uid: Uuid, addr: &SocketAddr) -> RResult<St
         args: cmd.args,
         addr,
         uid,
+        session,
     };
     handler(h_info).map(|r| r.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(handler: Handler, uid: Uuid, args: &[(&'static str, &str)]) -> HResult {
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let mut session = Session::default();
+        handler(HandleInfo {
+            args: args
+                .iter()
+                .map(|(k, v)| ((*k).into(), v.to_string()))
+                .collect(),
+            addr: &addr,
+            uid,
+            session: &mut session,
+        })
+    }
+
+    fn logged_in(name: &str) -> Uuid {
+        let uid = ClientDB::add_client("127.0.0.1:1234".parse().unwrap());
+        call(API::login, uid, &[("username", name), ("password", "pw")]).unwrap();
+        uid
+    }
+
+    lazy_static! {
+        // the one admin, shared by all tests
+        static ref ADMIN_UID: Uuid = {
+            ClientDB::init_test_db();
+            logged_in(&settings().admin)
+        };
+    }
+
+    #[test]
+    fn test_login() {
+        ClientDB::init_test_db();
+        let uid = logged_in("api_login");
+        assert_eq!(ClientDB::get_username(uid).as_deref(), Some("api_login"));
+        assert_eq!(ClientDB::get_username(Uuid::new_v4()), None);
+
+        let other = ClientDB::add_client("127.0.0.1:1235".parse().unwrap());
+        let creds = [("username", "api_login"), ("password", "nope")];
+        assert!(matches!(
+            call(API::login, other, &creds),
+            Err(SError::WrongPassword)
+        ));
+        let creds = [("username", "api_login"), ("password", "pw")];
+        assert!(matches!(
+            call(API::login, other, &creds),
+            Err(SError::AlreadyLoggedIn)
+        ));
+        for name in &["bad:name", "foo (to all)"] {
+            let creds = [("username", *name), ("password", "pw")];
+            assert!(matches!(
+                call(API::login, other, &creds),
+                Err(SError::InvalidLogin)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_log_line() {
+        let args = [("username", "foo"), ("password", "a|b")];
+        let cmd = Command {
+            cmd: "LOGIN".into(),
+            args: args
+                .iter()
+                .map(|(k, v)| ((*k).into(), v.to_string()))
+                .collect(),
+        };
+        assert_eq!(cmd.to_string(), "LOGIN|password=***|username=foo");
+    }
+
+    #[test]
+    fn test_echo() {
+        ClientDB::init_test_db();
+        let anon = ClientDB::add_client("127.0.0.1:1234".parse().unwrap());
+        // escaped by the text protocol only, see `Proto::encode_reply`
+        let echo = call(API::echo, anon, &[("msg", "a\n+#1 x")]).unwrap().0;
+        assert_eq!(echo, "a\n+#1 x");
+    }
+
+    #[test]
+    fn test_send() {
+        ClientDB::init_test_db();
+        let anon = ClientDB::add_client("127.0.0.1:1236".parse().unwrap());
+        let msg = [("username", "api_to"), ("msg", "hi")];
+        assert!(matches!(
+            call(API::send_to, anon, &msg),
+            Err(SError::NotLoggedIn)
+        ));
+
+        let from = logged_in("api_from");
+        let to = logged_in("api_to");
+        call(API::send_to, from, &msg).unwrap();
+        let jobs = ClientDB::get_all_client_jobs(to).unwrap();
+        match &jobs[..] {
+            [CliTask::SendMsg(_, sender, text)] => {
+                assert_eq!(sender, "api_from");
+                assert_eq!(text, "hi");
+            }
+            _ => panic!("unexpected jobs: {:?}", jobs),
+        }
+        let msg = [("username", "api_nobody"), ("msg", "hi")];
+        assert!(matches!(
+            call(API::send_to, from, &msg),
+            Err(SError::NoSuchUser)
+        ));
+    }
+
+    #[test]
+    fn test_history() {
+        ClientDB::init_test_db();
+        let alice = logged_in("hist_alice");
+        let bob = logged_in("hist_bob");
+        for text in &["one", "two|three"] {
+            let msg = [("username", "hist_bob"), ("msg", *text)];
+            call(API::send_to, alice, &msg).unwrap();
+        }
+        let query = [("with", "hist_alice"), ("limit", "1")];
+        let HandleResult(history) = call(API::history, bob, &query).unwrap();
+        assert!(history.ends_with("hist_alice -> hist_bob]: two|three"));
+        assert!(!history.contains("one"));
+
+        let query = [("with", "hist_alice"), ("limit", "1000")];
+        assert!(matches!(
+            call(API::history, bob, &query),
+            Err(SError::InvalidArg(_))
+        ));
+    }
+
+    #[test]
+    fn test_rooms() {
+        ClientDB::init_test_db();
+        let alice = logged_in("room_alice");
+        let bob = logged_in("room_bob");
+        let lab = [("room", "#room_lab")];
+        call(API::join_room, alice, &lab).unwrap();
+        call(API::join_room, bob, &[("room", "room_lab")]).unwrap();
+        assert!(matches!(
+            call(API::join_room, bob, &lab),
+            Err(SError::AlreadyInRoom)
+        ));
+        assert!(matches!(
+            call(API::join_room, bob, &[("room", "no spaces")]),
+            Err(SError::InvalidRoom)
+        ));
+        let HandleResult(members) = call(API::get_members, alice, &lab).unwrap();
+        assert_eq!(members, "room_alice *\nroom_bob *");
+
+        ClientDB::get_all_client_jobs(bob);
+        let msg = [("room", "room_lab"), ("msg", "hi")];
+        call(API::send_to_room, alice, &msg).unwrap();
+        match &ClientDB::get_all_client_jobs(bob).unwrap()[..] {
+            [CliTask::RoomMsg(_, room, sender, text)] => {
+                assert_eq!(room, "room_lab");
+                assert_eq!(sender, "room_alice");
+                assert_eq!(text, "hi");
+            }
+            jobs => panic!("unexpected jobs: {:?}", jobs),
+        }
+
+        call(API::part_room, bob, &lab).unwrap();
+        assert!(matches!(
+            call(API::send_to_room, bob, &msg),
+            Err(SError::NotInRoom)
+        ));
+        call(API::part_room, alice, &lab).unwrap();
+        assert!(matches!(
+            call(API::get_members, alice, &lab),
+            Err(SError::NoSuchRoom)
+        ));
+    }
+
+    #[test]
+    fn test_roles() {
+        ClientDB::init_test_db();
+        let admin_name = settings().admin.clone();
+        let admin = *ADMIN_UID;
+        let ta = logged_in("role_ta");
+        let anon = ClientDB::add_client("127.0.0.1:1237".parse().unwrap());
+        assert!(matches!(
+            API::check_role(anon, Role::Moderator),
+            Err(SError::NotLoggedIn)
+        ));
+        assert!(matches!(
+            API::check_role(ta, Role::Moderator),
+            Err(SError::PermissionDenied(Role::Moderator))
+        ));
+        API::check_role(admin, Role::Admin).unwrap();
+        assert_eq!(RULES["_GRANT"].1, Role::Admin);
+
+        let grant = [("username", "role_ta"), ("role", "moderator")];
+        call(API::grant_role, admin, &grant).unwrap();
+        API::check_role(ta, Role::Moderator).unwrap();
+        assert!(API::check_role(ta, Role::Admin).is_err());
+        call(API::revoke_role, admin, &[("username", "role_ta")]).unwrap();
+        assert_eq!(ClientDB::get_role(ta), Some(Role::User));
+
+        let demote = [("username", admin_name.as_str())];
+        assert!(call(API::revoke_role, admin, &demote).is_err());
+        assert!(!API::_help(Role::User).contains("_GRANT"));
+        assert!(API::_help(Role::Admin).contains("_GRANT"));
+    }
+
+    #[test]
+    fn test_moderation() {
+        ClientDB::init_test_db();
+        let admin = *ADMIN_UID;
+        let moder = logged_in("mod_moder");
+        let spammer = logged_in("mod_spammer");
+        let grant = [("username", "mod_moder"), ("role", "moderator")];
+        call(API::grant_role, admin, &grant).unwrap();
+
+        let mute = [("username", "mod_spammer"), ("minutes", "5")];
+        call(API::mute, moder, &mute).unwrap();
+        let msg = [("username", "mod_moder"), ("msg", "spam")];
+        assert!(matches!(
+            call(API::send_to, spammer, &msg),
+            Err(SError::Muted(5))
+        ));
+        let unmute = [("username", "mod_spammer"), ("minutes", "0")];
+        call(API::mute, moder, &unmute).unwrap();
+        call(API::send_to, spammer, &msg).unwrap();
+        let admin_name = settings().admin.clone();
+        let mute_up = [("username", admin_name.as_str()), ("minutes", "5")];
+        assert!(matches!(
+            call(API::mute, moder, &mute_up),
+            Err(SError::Untouchable)
+        ));
+
+        let ban = [("username", "mod_spammer"), ("reason", "spam")];
+        call(API::ban, moder, &ban).unwrap();
+        ClientDB::set_online_status(spammer, false);
+        let again = ClientDB::add_client("127.0.0.1:1238".parse().unwrap());
+        let creds = [("username", "mod_spammer"), ("password", "pw")];
+        assert!(matches!(
+            call(API::login, again, &creds),
+            Err(SError::Banned(reason)) if reason == "spam"
+        ));
+        call(API::unban, moder, &[("username", "mod_spammer")]).unwrap();
+        call(API::login, again, &creds).unwrap();
+
+        let addr = "10.1.2.3".parse().unwrap();
+        call(API::ban, moder, &[("addr", "10.1.2.3"), ("reason", "bot")]).unwrap();
+        assert_eq!(ClientDB::get_ip_ban(addr).unwrap().by, "mod_moder");
+        call(API::unban, moder, &[("addr", "10.1.2.3")]).unwrap();
+        assert!(ClientDB::get_ip_ban(addr).is_none());
+        assert!(matches!(
+            call(API::unban, moder, &[("addr", "10.1.2.3")]),
+            Err(SError::NotBanned)
+        ));
+    }
+}
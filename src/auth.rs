@@ -0,0 +1,164 @@
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+use crate::error::SError;
+
+const HASH_PREFIX: &str = "$argon2";
+const TOKEN_BYTES: usize = 32;
+
+/// What a user is allowed to do, each role includes the ones before it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = SError;
+
+    fn from_str(s: &str) -> Result<Role, SError> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(SError::InvalidArg(
+                "role must be user, moderator or admin".to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+pub enum Verdict {
+    Wrong,
+    Ok,
+    /// Password matched a legacy plaintext record, which should be rehashed
+    OkPlaintext,
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("can't hash password")
+        .to_string()
+}
+
+/// Whether a record saved before hashes were flagged holds one. Only for
+/// the one-off migration on load, a plaintext password may look like this.
+pub fn looks_hashed(stored: &str) -> bool {
+    stored.starts_with(HASH_PREFIX)
+}
+
+/// Check `password` against what is stored, `hashed` tells whether that
+/// is a hash or a legacy plaintext password
+pub fn verify_password(stored: &str, hashed: bool, password: &str) -> Verdict {
+    if !hashed {
+        return if bool::from(stored.as_bytes().ct_eq(password.as_bytes())) {
+            Verdict::OkPlaintext
+        } else {
+            Verdict::Wrong
+        };
+    }
+    let hash = match PasswordHash::new(stored) {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Malformed password hash in db: {}", e);
+            return Verdict::Wrong;
+        }
+    };
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Verdict::Ok,
+        Err(_) => Verdict::Wrong,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fresh random API token, shown to its owner once
+pub fn new_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+/// What is stored instead of an API token. Tokens are long and random,
+/// so a fast unsalted hash is enough and lookups stay cheap.
+pub fn hash_token(token: &str) -> String {
+    hex(&Blake2s256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_verify() {
+        let hash = hash_password("qwerty");
+        assert!(hash.starts_with(HASH_PREFIX));
+        assert_ne!(hash, hash_password("qwerty"));
+        assert!(matches!(verify_password(&hash, true, "qwerty"), Verdict::Ok));
+        assert!(matches!(verify_password(&hash, true, "qwertz"), Verdict::Wrong));
+
+        let token = new_token();
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert_ne!(token, new_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+    }
+
+    #[test]
+    fn test_roles() {
+        assert!(Role::Admin > Role::Moderator && Role::Moderator > Role::User);
+        assert_eq!("Moderator".parse::<Role>().unwrap(), Role::Moderator);
+        assert!("root".parse::<Role>().is_err());
+        assert_eq!(Role::Admin.to_string(), "admin");
+    }
+
+    #[test]
+    fn test_plaintext_migration() {
+        assert!(matches!(
+            verify_password("qwerty", false, "qwerty"),
+            Verdict::OkPlaintext
+        ));
+        assert!(matches!(
+            verify_password("qwerty", false, "qwert"),
+            Verdict::Wrong
+        ));
+        // a plaintext password that looks like a hash is still plaintext
+        let hash = hash_password("qwerty");
+        assert!(matches!(
+            verify_password(&hash, false, &hash),
+            Verdict::OkPlaintext
+        ));
+        assert!(matches!(
+            verify_password(&hash, false, "qwerty"),
+            Verdict::Wrong
+        ));
+    }
+}
@@ -0,0 +1,381 @@
+//! Reference console client: messages on the left, users on the right,
+//! the input line at the bottom. Pushes only ever land in the message
+//! pane, so they can't mess up what is being typed.
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use pi_server::{
+    chat_client::CResult, client::CliTask, error::ClientError, ChatClient, Event, User,
+};
+use ratatui::{
+    crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+/// How long to wait for a key press before looking at the socket
+const TICK: Duration = Duration::from_millis(50);
+const NET_WAIT: Duration = Duration::from_millis(10);
+const USERS_EVERY: Duration = Duration::from_secs(3);
+const HELP: &str = "text - to all, /msg <login> <text>, /room <room> <text>, /join <room>, \
+                    /part <room>, /login <login> <password>, /quit; PgUp/PgDn - scroll";
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Console chat client")]
+struct Cli {
+    /// Server address
+    #[arg(long, default_value = "localhost")]
+    host: String,
+    #[arg(long, default_value_t = 81)]
+    port: u16,
+    /// Log in right away, the password is taken from PI_CHAT_PASSWORD or asked for
+    #[arg(short, long)]
+    user: Option<String>,
+    /// Seconds of silence before a PING, must be less than the server's silent_timeout
+    #[arg(long)]
+    keepalive: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Input<'a> {
+    All(&'a str),
+    Msg(&'a str, &'a str),
+    Room(&'a str, &'a str),
+    Join(&'a str),
+    Part(&'a str),
+    Login(&'a str, &'a str),
+    Quit,
+    Bad,
+}
+
+fn parse_input(line: &str) -> Input<'_> {
+    let line = line.trim();
+    let rest = match line.strip_prefix('/') {
+        Some(rest) => rest,
+        None => return Input::All(line),
+    };
+    let (cmd, args) = rest.split_once(' ').unwrap_or((rest, ""));
+    let args = args.trim_start();
+    let pair = || args.split_once(' ').map(|(a, b)| (a, b.trim_start()));
+    match (cmd, pair()) {
+        ("msg", Some((to, msg))) => Input::Msg(to, msg),
+        ("room", Some((room, msg))) => Input::Room(room, msg),
+        ("login", Some((login, password))) => Input::Login(login, password),
+        ("join", _) if !args.is_empty() => Input::Join(args),
+        ("part", _) if !args.is_empty() => Input::Part(args),
+        ("quit", _) => Input::Quit,
+        _ => Input::Bad,
+    }
+}
+
+/// Cut a line into rows of `width` chars
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars = line.chars().collect::<Vec<char>>();
+    if chars.is_empty() || width == 0 {
+        return vec![String::new()];
+    }
+    chars.chunks(width).map(|c| c.iter().collect()).collect()
+}
+
+/// `HH:MM:SS` out of the server's `YYYY-MM-DD HH:MM:SS`
+fn time_of(date: &str) -> &str {
+    date.rsplit(' ').next().unwrap_or(date)
+}
+
+struct App {
+    client: Option<ChatClient>,
+    login: Option<String>,
+    messages: Vec<String>,
+    users: Vec<User>,
+    input: String,
+    /// Rows scrolled up from the bottom of the message pane
+    scroll: usize,
+    quit: bool,
+}
+
+impl App {
+    fn show<S: Into<String>>(&mut self, text: S) {
+        let text = text.into();
+        self.messages.extend(text.lines().map(String::from));
+    }
+
+    /// Run something on the connection, losing it on I/O errors
+    fn net<T>(&mut self, f: impl FnOnce(&mut ChatClient) -> CResult<T>) -> Option<T> {
+        let result = f(self.client.as_mut()?);
+        match result {
+            Ok(value) => Some(value),
+            Err(ClientError::Refused(_, e)) => {
+                self.show(format!("- {}", e));
+                None
+            }
+            Err(e) => {
+                self.show(format!("- {}", e));
+                self.client = None;
+                self.users.clear();
+                None
+            }
+        }
+    }
+
+    fn submit(&mut self) {
+        let line = std::mem::take(&mut self.input);
+        if line.trim().is_empty() {
+            return;
+        }
+        self.scroll = 0;
+        match parse_input(&line) {
+            Input::All(msg) => {
+                if self.net(|c| c.send_all(msg)).is_some() {
+                    self.show(format!("you (to all): {}", msg));
+                }
+            }
+            Input::Msg(to, msg) => {
+                if self.net(|c| c.send(to, msg)).is_some() {
+                    self.show(format!("you -> {}: {}", to, msg));
+                }
+            }
+            Input::Room(room, msg) => {
+                let room = room.trim_start_matches('#');
+                let args = [("room", room), ("msg", msg)];
+                if self.net(|c| c.command("SENDROOM", &args)).is_some() {
+                    self.show(format!("you (in #{}): {}", room, msg));
+                }
+            }
+            Input::Join(room) => self.room_command("JOIN", room),
+            Input::Part(room) => self.room_command("PART", room),
+            Input::Login(login, password) => {
+                if self.net(|c| c.login(login, password)).is_some() {
+                    self.login = Some(login.to_string());
+                    self.show(format!("logged in as {}", login));
+                }
+            }
+            Input::Quit => self.quit = true,
+            Input::Bad => self.show(HELP),
+        }
+    }
+
+    fn room_command(&mut self, cmd: &str, room: &str) {
+        let room = room.trim_start_matches('#');
+        if self.net(|c| c.command(cmd, &[("room", room)])).is_some() {
+            self.show(format!("{} #{}", cmd.to_lowercase(), room));
+        }
+    }
+
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Msg(CliTask::SendMsg(date, from, msg)) => {
+                self.show(format!("[{}] {}: {}", time_of(&date), from, msg))
+            }
+            Event::Msg(CliTask::Broadcast(date, from, msg)) => {
+                self.show(format!("[{}] {} (to all): {}", time_of(&date), from, msg))
+            }
+            Event::Msg(CliTask::RoomMsg(date, room, from, msg)) => self.show(format!(
+                "[{}] {} (in #{}): {}",
+                time_of(&date),
+                from,
+                room,
+                msg
+            )),
+            Event::Msg(CliTask::Exit) => (),
+            Event::Timeout => self.show("- disconnected for silence"),
+            Event::Countdown(seconds, reason) => self.show(format!(
+                "- server stops in {} s{}",
+                seconds,
+                reason.map(|r| format!(": {}", r)).unwrap_or_default()
+            )),
+            Event::Shutdown => self.show("- server is shutting down"),
+        }
+    }
+
+    fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Enter => self.submit(),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::PageUp => self.scroll += 5,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(5),
+            _ => (),
+        }
+    }
+
+    /// Read whatever the server has sent by now
+    fn poll_net(&mut self) {
+        while let Some(Some(event)) = self.net(|c| c.next_event(Some(NET_WAIT))) {
+            self.on_event(event);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, bottom] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [chat, people] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(24)]).areas(top);
+
+        let width = chat.width.saturating_sub(2) as usize;
+        let height = chat.height.saturating_sub(2) as usize;
+        let rows = self
+            .messages
+            .iter()
+            .flat_map(|m| wrap(m, width))
+            .collect::<Vec<String>>();
+        let end = rows
+            .len()
+            .saturating_sub(self.scroll)
+            .max(height.min(rows.len()));
+        let start = end.saturating_sub(height);
+        let lines = rows[start..end]
+            .iter()
+            .map(|row| {
+                let style = if row.starts_with("- ") {
+                    Style::default().fg(Color::Red)
+                } else if row.starts_with("you") {
+                    Style::default().fg(Color::DarkGray)
+                } else {
+                    Style::default()
+                };
+                Line::styled(row.as_str(), style)
+            })
+            .collect::<Vec<Line>>();
+        let title = match (&self.client, &self.login) {
+            (None, _) => " offline ".to_string(),
+            (Some(_), Some(login)) => format!(" {} ", login),
+            (Some(_), None) => " not logged in (/login) ".to_string(),
+        };
+        let messages =
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(messages, chat);
+
+        let users = self
+            .users
+            .iter()
+            .map(|u| {
+                if u.online {
+                    Line::styled(
+                        u.name.as_str(),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    )
+                } else {
+                    Line::styled(u.name.as_str(), Style::default().fg(Color::DarkGray))
+                }
+            })
+            .collect::<Vec<Line>>();
+        let users =
+            Paragraph::new(users).block(Block::default().borders(Borders::ALL).title(" users "));
+        frame.render_widget(users, people);
+
+        // keep the end of a long input visible
+        let room = bottom.width.saturating_sub(2) as usize;
+        let skip = self
+            .input
+            .chars()
+            .count()
+            .saturating_sub(room.saturating_sub(1));
+        let shown = self.input.chars().skip(skip).collect::<String>();
+        let cursor = (bottom.x + 1 + shown.chars().count() as u16, bottom.y + 1);
+        let input = Paragraph::new(shown).block(Block::default().borders(Borders::ALL));
+        frame.render_widget(input, bottom);
+        frame.set_cursor_position(cursor);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        let mut users_at = Instant::now();
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(TICK)? {
+                if let TermEvent::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.on_key(key.code, key.modifiers);
+                    }
+                }
+            }
+            self.poll_net();
+            if Instant::now() >= users_at {
+                if let Some(users) = self.net(|c| c.users()) {
+                    self.users = users;
+                }
+                users_at = Instant::now() + USERS_EVERY;
+            }
+        }
+        if let Some(client) = self.client.take() {
+            client.exit().ok();
+        }
+        Ok(())
+    }
+}
+
+fn ask_password(user: &str) -> String {
+    if let Ok(password) = env::var("PI_CHAT_PASSWORD") {
+        return password;
+    }
+    print!("Password for {}: ", user);
+    io::stdout().flush().ok();
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password).ok();
+    password.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let mut client = ChatClient::connect((cli.host.as_str(), cli.port)).unwrap_or_else(|e| {
+        eprintln!("Can't connect to {}:{}: {}", cli.host, cli.port, e);
+        process::exit(1)
+    });
+    if let Some(secs) = cli.keepalive {
+        client.set_keepalive(Some(Duration::from_secs(secs)));
+    }
+    if let Some(user) = &cli.user {
+        let password = ask_password(user);
+        if let Err(e) = client.login(user, &password) {
+            eprintln!("Can't log in: {}", e);
+            process::exit(1)
+        }
+    }
+    let mut app = App {
+        client: Some(client),
+        login: cli.user,
+        messages: vec![HELP.to_string()],
+        users: vec![],
+        input: String::new(),
+        scroll: 0,
+        quit: false,
+    };
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(parse_input("hi all "), Input::All("hi all"));
+        assert_eq!(
+            parse_input("/msg foo  hi there"),
+            Input::Msg("foo", "hi there")
+        );
+        assert_eq!(parse_input("/room #lab1 ok"), Input::Room("#lab1", "ok"));
+        assert_eq!(parse_input("/login foo p w"), Input::Login("foo", "p w"));
+        assert_eq!(parse_input("/join lab1"), Input::Join("lab1"));
+        assert_eq!(parse_input("/msg foo"), Input::Bad);
+        assert_eq!(parse_input("/what"), Input::Bad);
+        assert_eq!(parse_input("/quit"), Input::Quit);
+        assert_eq!(wrap("абвгд", 2), vec!["аб", "вг", "д"]);
+        assert_eq!(time_of("2024-01-02 10:00:00"), "10:00:00");
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::{
+    client::CliTask,
+    config::*,
+    error::ClientError,
+    protocol::{escape, unescape},
+};
+
+/// The server drops connections silent for 40 seconds by default
+const KEEPALIVE: Duration = Duration::from_secs(20);
+/// Correlation id of the keepalive pings, their replies are dropped
+const PING_ID: &str = "keepalive";
+
+pub type CResult<T> = Result<T, ClientError>;
+
+/// Something the server sent on its own
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// `SendMsg`, `Broadcast` or `RoomMsg`
+    Msg(CliTask),
+    Timeout,
+    /// The server stops in that many seconds, maybe for a reason
+    Countdown(u64, Option<String>),
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    /// Login, or the address of a client that hasn't logged in
+    pub name: String,
+    pub online: bool,
+}
+
+/// Blocking client speaking the text protocol. Pushes that arrive while
+/// waiting for a reply are queued and handed out by `next_event`, and a
+/// PING is sent whenever the connection has been quiet for too long.
+pub struct ChatClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    line: Vec<u8>,
+    events: VecDeque<Event>,
+    keepalive: Option<Duration>,
+    last_sent: Instant,
+    next_id: u64,
+    /// Ids of the requests still waiting for replies, oldest first
+    pending: VecDeque<String>,
+}
+
+/// Parse a push line, `None` if it isn't one
+fn parse_push(line: &str) -> Option<Event> {
+    let push = line.strip_prefix(PUSH)?;
+    match push {
+        TIMEOUT_MSG => return Some(Event::Timeout),
+        SHUTDOWN_MSG => return Some(Event::Shutdown),
+        _ => (),
+    }
+    // SHUTDOWN in N s[: reason]
+    if let Some(rest) = push.strip_prefix(SHUTDOWN_MSG) {
+        let (seconds, reason) = rest.strip_prefix(" in ")?.split_once(" s")?;
+        let reason = match reason.strip_prefix(": ") {
+            Some(reason) => Some(unescape(reason)?),
+            None => None,
+        };
+        return Some(Event::Countdown(seconds.parse().ok()?, reason));
+    }
+    // MSGFROM [date time from] (len): msg, neither logins nor rooms have ':'
+    let rest = push.strip_prefix("MSGFROM [")?;
+    let mut words = rest.splitn(3, ' ');
+    let date = format!("{} {}", words.next()?, words.next()?);
+    let (head, msg) = words.next()?.split_once(": ")?;
+    let (from, _len) = head.rsplit_once("] (")?;
+    let msg = unescape(msg)?;
+    // new logins can't end with ')', so one there is the server's suffix,
+    // and room names can't have '(' or ')'
+    let room_mark = format!(" (in {}", ROOM_MARK);
+    let task = if let Some(from) = from.strip_suffix(TO_ALL) {
+        CliTask::Broadcast(date, from.to_string(), msg)
+    } else if let Some((from, room)) = from
+        .strip_suffix(')')
+        .and_then(|f| f.rsplit_once(&room_mark))
+    {
+        CliTask::RoomMsg(date, room.to_string(), from.to_string(), msg)
+    } else {
+        CliTask::SendMsg(date, from.to_string(), msg)
+    };
+    Some(Event::Msg(task))
+}
+
+impl ChatClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> CResult<ChatClient> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(ChatClient {
+            stream,
+            reader,
+            line: vec![],
+            events: VecDeque::new(),
+            keepalive: Some(KEEPALIVE),
+            last_sent: Instant::now(),
+            next_id: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// How long the connection may stay quiet before a PING, `None` disables
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+    }
+
+    fn write_line(&mut self, cmd: &str, args: &[(&str, &str)], id: &str) -> CResult<()> {
+        let mut line = cmd.to_string();
+        for (k, v) in args {
+            line += &format!("|{}={}", k, escape(v));
+        }
+        line += &format!("|id={}\n", id);
+        self.stream.write_all(line.as_bytes())?;
+        self.last_sent = Instant::now();
+        self.pending.push_back(id.to_string());
+        Ok(())
+    }
+
+    /// Next line from the server, `None` if nothing came before `deadline`
+    fn read_line(&mut self, deadline: Option<Instant>) -> CResult<Option<String>> {
+        loop {
+            let timeout = match deadline {
+                Some(d) => match d.checked_duration_since(Instant::now()) {
+                    Some(t) if !t.is_zero() => Some(t),
+                    _ => return Ok(None),
+                },
+                None => None,
+            };
+            self.stream.set_read_timeout(timeout)?;
+            // a line cut by the timeout stays in `self.line` for the next call
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return Err(ClientError::Closed),
+                Ok(_) if self.line.ends_with(b"\n") => {
+                    let line = String::from_utf8_lossy(&self.line)
+                        .trim_end_matches(&['\r', '\n'][..])
+                        .to_string();
+                    self.line.clear();
+                    return Ok(Some(line));
+                }
+                Ok(_) => return Err(ClientError::Closed),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Queue a push, or return the reply with its id
+    fn sort_line(&mut self, line: String) -> CResult<Option<(String, CResult<String>)>> {
+        if let Some(event) = parse_push(&line) {
+            self.events.push_back(event);
+            return Ok(None);
+        }
+        let (ok, rest) = if let Some(rest) = line.strip_prefix(SUCCESS) {
+            (true, rest)
+        } else if let Some(rest) = line.strip_prefix(FAIL) {
+            (false, rest)
+        } else {
+            return Err(ClientError::Protocol(line));
+        };
+        let (id, data) = match rest.strip_prefix(ID_MARK) {
+            Some(rest) => rest
+                .split_once(' ')
+                .map(|(id, data)| (id.to_string(), data))
+                .ok_or_else(|| ClientError::Protocol(line.clone()))?,
+            // the server couldn't read the request, such as a line too
+            // long, and replies in order, so it's the oldest one
+            None => match self.pending.front() {
+                Some(id) => (id.clone(), rest),
+                None => return Err(ClientError::Protocol(line)),
+            },
+        };
+        if let Some(i) = self.pending.iter().position(|p| *p == id) {
+            self.pending.remove(i);
+        }
+        let reply = match unescape(data) {
+            Some(data) if ok => Ok(data),
+            Some(data) => match data.split_once(' ') {
+                Some((code, msg)) => Err(ClientError::Refused(code.to_string(), msg.to_string())),
+                None => Err(ClientError::Protocol(line.clone())),
+            },
+            None => Err(ClientError::Protocol(line.clone())),
+        };
+        Ok(Some((id, reply)))
+    }
+
+    /// Run any command and wait for its reply, the data is unescaped
+    pub fn command(&mut self, cmd: &str, args: &[(&str, &str)]) -> CResult<String> {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        self.write_line(cmd, args, &id)?;
+        loop {
+            let line = self.read_line(None)?.ok_or(ClientError::Closed)?;
+            match self.sort_line(line)? {
+                Some((reply_id, reply)) if reply_id == id => return reply,
+                _ => (),
+            }
+        }
+    }
+
+    /// Wait for a push up to `timeout` (forever if `None`), pinging the
+    /// server meanwhile so that it doesn't drop the connection
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> CResult<Option<Event>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            let ping_at = self.keepalive.map(|k| self.last_sent + k);
+            if ping_at.is_some_and(|at| at <= Instant::now()) {
+                self.write_line("PING", &[], PING_ID)?;
+                continue;
+            }
+            let wait = match (deadline, ping_at) {
+                (Some(d), Some(p)) => Some(d.min(p)),
+                (d, p) => d.or(p),
+            };
+            match self.read_line(wait)? {
+                Some(line) => {
+                    self.sort_line(line)?;
+                }
+                None if deadline.is_some_and(|d| d <= Instant::now()) => return Ok(None),
+                None => (),
+            }
+        }
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) -> CResult<()> {
+        self.command("LOGIN", &[("username", username), ("password", password)])?;
+        Ok(())
+    }
+
+    pub fn send(&mut self, username: &str, msg: &str) -> CResult<()> {
+        self.command("SEND", &[("username", username), ("msg", msg)])?;
+        Ok(())
+    }
+
+    pub fn send_all(&mut self, msg: &str) -> CResult<()> {
+        self.command("SNDALL", &[("msg", msg)])?;
+        Ok(())
+    }
+
+    pub fn ping(&mut self) -> CResult<()> {
+        self.command("PING", &[])?;
+        Ok(())
+    }
+
+    /// Everyone the server knows, you included
+    pub fn users(&mut self) -> CResult<Vec<User>> {
+        let online = format!(" {}", ONLINE);
+        let users = self.command("USERS", &[])?;
+        Ok(users
+            .lines()
+            .map(|line| {
+                let name = line.strip_suffix(&online).unwrap_or(line);
+                User {
+                    name: name.strip_suffix(" (you)").unwrap_or(name).to_string(),
+                    online: name.len() != line.len(),
+                }
+            })
+            .collect())
+    }
+
+    /// Say goodbye, the server closes the connection after the reply
+    pub fn exit(mut self) -> CResult<()> {
+        self.command("EXIT", &[])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_parse_push() {
+        let room = "!MSGFROM [2024-01-02 10:00:00 a b (in #lab1)] (8): x\\|y: z";
+        match parse_push(room) {
+            Some(Event::Msg(CliTask::RoomMsg(date, room, from, msg))) => {
+                assert_eq!(date, "2024-01-02 10:00:00");
+                assert_eq!((room.as_str(), from.as_str()), ("lab1", "a b"));
+                assert_eq!(msg, "x|y: z");
+            }
+            e => panic!("{:?}", e),
+        }
+        let all = "!MSGFROM [2024-01-02 10:00:00 foo (to all)] (4): a\\nb";
+        match parse_push(all) {
+            Some(Event::Msg(CliTask::Broadcast(_, from, msg))) => {
+                assert_eq!(from, "foo");
+                assert_eq!(msg, "a\nb");
+            }
+            e => panic!("{:?}", e),
+        }
+        // a login that looks like a room message, sent to a room
+        let spoof = "!MSGFROM [2024-01-02 10:00:00 x (in #a (to all (in #lab1)] (1): y";
+        match parse_push(spoof) {
+            Some(Event::Msg(CliTask::RoomMsg(_, room, from, _))) => {
+                assert_eq!((room.as_str(), from.as_str()), ("lab1", "x (in #a (to all"));
+            }
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(parse_push("!TIMEOUT"), Some(Event::Timeout)));
+        match parse_push("!SHUTDOWN in 30 s: update\\nreboot") {
+            Some(Event::Countdown(30, Some(reason))) => assert_eq!(reason, "update\nreboot"),
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(
+            parse_push("!SHUTDOWN in 0 s"),
+            Some(Event::Countdown(0, None))
+        ));
+        assert!(parse_push("+#1 !MSGFROM").is_none());
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(sock.try_clone().unwrap());
+            let mut sock = sock;
+            let mut expect = |line: &str, replies: &[&str]| {
+                let mut got = String::new();
+                reader.read_line(&mut got).unwrap();
+                assert_eq!(got, line);
+                for reply in replies {
+                    sock.write_all(format!("{}\n", reply).as_bytes()).unwrap();
+                }
+            };
+            expect("LOGIN|username=bot|password=p\\|w|id=1\n", &["+#1 "]);
+            expect(
+                "USERS|id=2\n",
+                &[
+                    "!MSGFROM [2024-01-02 10:00:00 foo] (2): hi",
+                    "+#2 bot (you) *\\nfoo",
+                ],
+            );
+            expect(
+                "SEND|username=nobody|msg=hi|id=3\n",
+                &["-#3 E430 No such user"],
+            );
+            // framing refuses the line before the id is read
+            expect(
+                &format!("SNDALL|msg={}|id=4\n", "x".repeat(2000)),
+                &["-E413 Line too long: 256 bytes at max"],
+            );
+            expect("PING|id=keepalive\n", &["+#keepalive ", "!SHUTDOWN"]);
+        });
+
+        let mut client = ChatClient::connect(addr).unwrap();
+        client.set_keepalive(Some(Duration::from_millis(100)));
+        client.login("bot", "p|w").unwrap();
+        let users = client.users().unwrap();
+        assert_eq!(
+            users[0],
+            User {
+                name: "bot".into(),
+                online: true
+            }
+        );
+        assert_eq!(
+            users[1],
+            User {
+                name: "foo".into(),
+                online: false
+            }
+        );
+        match client.send("nobody", "hi") {
+            Err(ClientError::Refused(code, e)) => {
+                assert_eq!((code.as_str(), e.as_str()), ("E430", "No such user"))
+            }
+            r => panic!("{:?}", r),
+        }
+        match client.send_all(&"x".repeat(2000)) {
+            Err(ClientError::Refused(code, _)) => assert_eq!(code, "E413"),
+            r => panic!("{:?}", r),
+        }
+        // the push that came before USERS' reply is kept
+        assert!(matches!(
+            client.next_event(Some(Duration::ZERO)).unwrap(),
+            Some(Event::Msg(CliTask::SendMsg(..)))
+        ));
+        let event = client.next_event(Some(Duration::from_secs(5))).unwrap();
+        assert!(matches!(event, Some(Event::Shutdown)));
+        server.join().unwrap();
+    }
+}
@@ -1,12 +1,19 @@
 use std::io::{self, Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::panic;
-use std::thread::sleep;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 use crate::{
-    api::process_command, config::*, db::ClientDB, error::SError, protocol::parse_request,
+    api::process_command,
+    codec::{Proto, Push, Scope},
+    config::*,
+    db::ClientDB,
+    framing::LineReader,
+    irc::{self, IrcState},
+    lang::Lang,
+    server,
+    transport::Transport,
 };
 
 use serde::{Deserialize, Serialize};
@@ -15,9 +22,66 @@ use serde::{Deserialize, Serialize};
 pub enum CliTask {
     // date, from, msg
     SendMsg(String, String, String),
+    // date, from, msg sent to all
+    Broadcast(String, String, String),
+    // date, room, from, msg
+    RoomMsg(String, String, String, String),
     Exit,
 }
 
+/// Per-connection settings changed by the commands themselves
+#[derive(Default)]
+pub struct Session {
+    pub proto: Proto,
+    pub lang: Lang,
+    /// Set for connections speaking IRC instead of our protocol
+    pub irc: Option<IrcState>,
+}
+
+impl Session {
+    pub fn irc() -> Session {
+        Session {
+            irc: Some(IrcState::default()),
+            ..Session::default()
+        }
+    }
+
+    /// Stands in while the session is away with a slow command, enough
+    /// to encode pushes the same way
+    fn placeholder(&self) -> Session {
+        Session {
+            proto: self.proto,
+            lang: self.lang,
+            irc: self.irc.as_ref().map(|_| IrcState::default()),
+        }
+    }
+
+    /// LOGIN hashes the password, which would stall every connection if
+    /// done on the reactor. IRC logs in somewhere during registration.
+    fn is_slow(&self, line: &str) -> bool {
+        match self.irc.as_ref() {
+            Some(irc) => !irc.is_registered(),
+            None => self
+                .proto
+                .decode(line)
+                .is_ok_and(|(c, _)| c.cmd.trim().eq_ignore_ascii_case("LOGIN")),
+        }
+    }
+}
+
+/// A request line to handle off the reactor thread, see `server::run_slow`
+pub type Slow = Box<dyn FnOnce(&mut Session) -> Vec<String> + Send>;
+
+/// Account of a connection that is gone goes offline, an anonymous one
+/// is forgotten
+pub fn release(uid: Uuid) {
+    if ClientDB::is_logged_in(uid) {
+        ClientDB::set_online_status(uid, false);
+    } else {
+        ClientDB::remove_cli(uid);
+    }
+}
+
 fn try_append_username(uid: Uuid, addr: &SocketAddr) -> String {
     match ClientDB::get_username(uid) {
         Some(n) => format!("{} ({})", addr, n),
@@ -26,140 +90,293 @@ fn try_append_username(uid: Uuid, addr: &SocketAddr) -> String {
 }
 
 pub struct Client {
-    conn: TcpStream,
+    conn: Box<dyn Transport>,
     addr: SocketAddr,
     uid: Uuid,
+    last_seen: Instant,
+    reader: LineReader,
+    session: Session,
+    outbox: Vec<u8>,
+    closed: bool,
+    /// `SHUTDOWN` is sent, the socket is closed once it's flushed
+    closing: bool,
+    /// An IRC client got a PING for being silent
+    pinged: bool,
+    /// The session is away with a slow command, lines wait for it
+    busy: bool,
 }
 
 impl Client {
-    pub fn handle(stream: TcpStream, addr: SocketAddr) {
+    pub fn new(stream: Box<dyn Transport>, addr: SocketAddr, session: Session) -> Client {
         let client_uid = ClientDB::add_client(addr);
-        let instance = Client {
+        let max_line_len = match session.irc {
+            Some(_) => settings().max_line_len.max(irc::MAX_LINE),
+            None => settings().max_line_len,
+        };
+        Client {
             conn: stream,
-            addr: addr.clone(),
+            addr,
             uid: client_uid,
-        };
-        panic::catch_unwind(|| instance._handle_req()).ok();
-    }
-
-    fn _handle_req(mut self) {
-        let mut silence_counter = 0; // instead of not working with non-blocking sockets timeout
-        let mut drop_trigger = false;
-        let mut data = [0u8; CMD_BUF_SIZE];
-        self.conn
-            .set_nonblocking(true)
-            .expect("Can't make socket non-blocking");
-        // self.conn
-        //     .set_read_timeout(Some(Duration::from_secs(SILENT_CONN_TIMEOUT)))
-        //     .expect("Can't set timeout");
-        loop {
-            data.iter_mut().for_each(|e| *e = 0u8);
-            let read_result = Read::by_ref(&mut self.conn)
-                .take(CMD_BUF_SIZE as u64)
-                .read(&mut data);
-            match read_result {
-                Ok(size) => {
-                    silence_counter = 0;
-                    if size == 0 {
-                        info!(
-                            "Connection with {} is closed",
-                            try_append_username(self.uid, &self.addr)
-                        );
-                        break;
-                    }
-                    if size < CMD_BUF_SIZE {
-                        if drop_trigger {
-                            drop_trigger = false;
-                        }
-                    }
-                    if drop_trigger {
-                        continue;
-                    }
-                    if size == CMD_BUF_SIZE {
-                        drop_trigger = true;
-                    }
+            last_seen: Instant::now(),
+            reader: LineReader::new(max_line_len),
+            session,
+            outbox: vec![],
+            closed: false,
+            closing: false,
+            pinged: false,
+            busy: false,
+        }
+    }
 
-                    let cmd = String::from_utf8_lossy(&data).into_owned();
-                    let cmd = cmd.trim_matches(char::from(0)).trim().to_string();
-                    if cmd.len() == 0 {
-                        continue;
-                    }
-                    let _log_msg = format!(
-                        "Cmd from {}: {}",
-                        try_append_username(self.uid, &self.addr),
-                        &cmd
+    pub fn uid(&self) -> Uuid {
+        self.uid
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Moment after which a silent client gets `TIMEOUT`
+    pub fn deadline(&self) -> Instant {
+        self.last_seen + Duration::from_secs(settings().silent_timeout)
+    }
+
+    pub fn on_readable(&mut self) {
+        let mut data = vec![0u8; settings().read_buf_size];
+        while !self.closed {
+            match self.conn.read(&mut data) {
+                Ok(0) => {
+                    info!(
+                        "Connection with {} is closed",
+                        try_append_username(self.uid, &self.addr)
                     );
-                    let response = parse_request(&cmd)
-                        .map_err(|e| SError::SyntaxError(e.to_string()))
-                        .and_then(|(_, c)| process_command(c, self.uid, &self.addr));
-                    let response = match response {
-                        Ok(resp) => {
-                            if cmd.to_lowercase() != "ping" {
-                                info!("{}", _log_msg);
-                            }
-                            format!("{}{}", SUCCESS, resp)
-                        }
-                        Err(e) => {
-                            error!("{} ({})", _log_msg, &e);
-                            format!("{}{}", FAIL, e)
-                        }
-                    };
-                    self.send_response(response);
+                    self.closed = true;
+                }
+                Ok(size) => {
+                    self.last_seen = Instant::now();
+                    self.pinged = false;
+                    self.reader.feed(&data[..size]);
+                    self.handle_lines();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Error in {} occured: {}", self.addr, e);
+                    self.closed = true;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    if silence_counter / (1000 / HALT_MS as usize) >= SILENT_CONN_TIMEOUT {
-                        self.send_response(TIMEOUT_MSG);
+            }
+        }
+    }
+
+    fn handle_lines(&mut self) {
+        while !self.busy {
+            let line = match self.reader.next_line() {
+                Some(line) => line,
+                None => return,
+            };
+            if self.closed || self.closing {
+                return;
+            }
+            match line {
+                Ok(cmd) => {
+                    let handled =
+                        panic::catch_unwind(AssertUnwindSafe(|| self.handle_cmd(cmd.trim())));
+                    if handled.is_err() {
                         self.shutdown();
                     }
-                    sleep(Duration::from_millis(HALT_MS));
-                    silence_counter += 1;
                 }
                 Err(e) => {
-                    error!("Error in {} occured: {}", self.addr, e.to_string());
-                    break;
+                    error!("Bad input from {}: {}", self.addr, &e);
+                    let reply = match self.session.irc {
+                        Some(_) => irc::notice(&self.session, &e),
+                        None => self.session.proto.encode_reply(self.session.lang, None, &Err(e)),
+                    };
+                    self.send_response(reply);
                 }
             }
-            self.apply_jobs();
         }
     }
 
-    fn apply_jobs(&mut self) {
+    fn handle_cmd(&mut self, cmd: &str) {
+        if cmd.is_empty() {
+            return;
+        }
+        if self.session.is_slow(cmd) {
+            let placeholder = self.session.placeholder();
+            let session = std::mem::replace(&mut self.session, placeholder);
+            let (line, uid, addr) = (cmd.to_string(), self.uid, self.addr);
+            self.busy = true;
+            server::run_slow(
+                self.uid,
+                session,
+                Box::new(move |session| respond(&line, uid, &addr, session)),
+            );
+            return;
+        }
+        for reply in respond(cmd, self.uid, &self.addr, &mut self.session) {
+            self.send_response(reply);
+        }
+    }
+
+    /// A slow command is done, go on with the lines that came meanwhile
+    pub fn resume(&mut self, session: Session, replies: Option<Vec<String>>) {
+        self.session = session;
+        self.busy = false;
+        match replies {
+            Some(replies) => replies.into_iter().for_each(|r| self.send_response(r)),
+            None => return self.shutdown(),
+        }
+        self.apply_jobs();
+        self.handle_lines();
+    }
+
+    pub fn apply_jobs(&mut self) {
+        // whatever is left stays queued for the next login, and pushes
+        // wait for the reply to a slow command
+        if self.closing || self.busy {
+            return;
+        }
         if let Some(jobs) = ClientDB::get_all_client_jobs(self.uid) {
             jobs.into_iter().for_each(|job| match job {
-                CliTask::Exit => {
-                    self.send_response(SHUTDOWN_MSG);
-                    self.shutdown();
-                }
-                CliTask::SendMsg(date, sender, msg) => {
-                    let full_msg = format!(
-                        "MSGFROM [{} {}] ({}): {}",
-                        date,
-                        sender,
-                        msg.chars().count(),
-                        msg
-                    );
-                    self.send_response(full_msg)
-                }
+                CliTask::Exit => self.exit(),
+                CliTask::SendMsg(date, from, msg) => self.push(Push::Msg {
+                    date,
+                    scope: Scope::Direct,
+                    from,
+                    msg,
+                }),
+                CliTask::Broadcast(date, from, msg) => self.push(Push::Msg {
+                    date,
+                    scope: Scope::All,
+                    from,
+                    msg,
+                }),
+                CliTask::RoomMsg(date, room, from, msg) => self.push(Push::Msg {
+                    date,
+                    scope: Scope::Room(room),
+                    from,
+                    msg,
+                }),
             });
         }
     }
 
+    /// Send `SHUTDOWN` and close the connection as soon as it's delivered
+    pub fn exit(&mut self) {
+        if self.closing {
+            return;
+        }
+        self.push(Push::Shutdown);
+        self.closing = true;
+        self.flush();
+    }
+
+    /// Warn that the server stops in `seconds`
+    pub fn countdown(&mut self, seconds: u64, reason: Option<String>) {
+        self.push(Push::Countdown { seconds, reason });
+    }
+
+    pub fn timeout(&mut self) {
+        // the peer doesn't take its SHUTDOWN, drop it
+        if self.closing {
+            self.shutdown();
+            return;
+        }
+        // IRC clients only speak when they have something to say,
+        // so they get a chance to answer a PING first
+        if self.session.irc.is_some() && !self.pinged {
+            self.pinged = true;
+            self.last_seen = Instant::now();
+            self.send_response(format!("PING :{}", irc::SERVER_NAME));
+            return;
+        }
+        self.push(Push::Timeout);
+        self.shutdown();
+    }
+
+    fn push(&mut self, push: Push) {
+        let data = match self.session.irc {
+            Some(_) => irc::encode_push(&self.session, &push),
+            None => Some(self.session.proto.encode_push(&push)),
+        };
+        if let Some(data) = data {
+            self.send_response(data);
+        }
+    }
+
+    /// Write out as much of the pending output as the socket takes
+    pub fn flush(&mut self) {
+        // TLS records left over from the last attempt go first
+        if self.conn.flush().is_err() {
+            return;
+        }
+        while !self.outbox.is_empty() {
+            match self.conn.write(&self.outbox) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.outbox.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        if self.closing && self.outbox.is_empty() && !self.closed && self.conn.flush().is_ok() {
+            self.shutdown();
+        }
+    }
+
     fn send_response<S: Into<String>>(&mut self, data: S) {
-        self.conn.write((data.into() + "\n").as_bytes()).ok();
+        if self.closed || self.closing {
+            return;
+        }
+        let eol = if self.session.irc.is_some() { "\r\n" } else { "\n" };
+        self.outbox.extend((data.into() + eol).as_bytes());
+        self.flush();
     }
 
     fn shutdown(&mut self) {
-        self.conn.shutdown(Shutdown::Both).ok();
+        self.conn.shutdown();
+        self.closed = true;
     }
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        if ClientDB::is_logged_in(self.uid) {
-            ClientDB::update_cmd_ts(self.uid);
-            ClientDB::set_online_status(self.uid, false);
-        } else {
-            ClientDB::remove_cli(self.uid);
-        }
+        release(self.uid);
+    }
+}
+
+/// Replies to a request line
+fn respond(cmd: &str, uid: Uuid, addr: &SocketAddr, session: &mut Session) -> Vec<String> {
+    if session.irc.is_some() {
+        return irc::handle(cmd, uid, addr, session);
     }
+    let who = try_append_username(uid, addr);
+    // the reply goes out in the format the request came in,
+    // even if the command has just switched the protocol
+    let proto = session.proto;
+    let (response, id) = match proto.decode(cmd) {
+        Ok((c, id)) => {
+            // logged decoded, so that passwords are hidden
+            let line = c.to_string();
+            let ping = c.cmd.eq_ignore_ascii_case("ping");
+            let response = process_command(c, uid, addr, session);
+            match &response {
+                Ok(_) if ping => (),
+                Ok(_) => info!("Cmd from {}: {}", who, line),
+                Err(e) => error!("Cmd from {}: {} ({})", who, line, e),
+            }
+            (response, id)
+        }
+        Err(e) => {
+            // the error may quote the line
+            error!("Bad request from {}: {}", who, e.code());
+            (Err(e), None)
+        }
+    };
+    vec![proto.encode_reply(session.lang, id.as_ref(), &response)]
 }
@@ -0,0 +1,368 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::{
+    api::{Command, RResult},
+    config::*,
+    error::SError,
+    lang::Lang,
+    protocol::{escape, parse_request},
+};
+
+/// Wire format spoken on a connection, switched with `PROTO|mode=`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Proto {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Proto {
+    type Err = SError;
+
+    fn from_str(s: &str) -> Result<Proto, SError> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Proto::Text),
+            "json" => Ok(Proto::Json),
+            _ => Err(SError::UnknownProto),
+        }
+    }
+}
+
+/// Who a message was sent to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    Direct,
+    All,
+    Room(String),
+}
+
+/// Message sent by the server on its own, not as a reply to a command
+pub enum Push {
+    Msg {
+        date: String,
+        scope: Scope,
+        from: String,
+        msg: String,
+    },
+    Timeout,
+    /// The server stops in `seconds`
+    Countdown {
+        seconds: u64,
+        reason: Option<String>,
+    },
+    Shutdown,
+}
+
+/// Strings with escapes in them can't be borrowed from the line
+#[derive(Deserialize)]
+struct JsonRequest<'a> {
+    #[serde(borrow)]
+    cmd: Cow<'a, str>,
+    #[serde(borrow, default)]
+    args: HashMap<Cow<'a, str>, Value>,
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonOut<'a> {
+    Response {
+        id: Option<&'a Value>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Msg {
+        date: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<&'a str>,
+        #[serde(skip_serializing_if = "is_false")]
+        to_all: bool,
+        from: &'a str,
+        msg: &'a str,
+    },
+    Timeout,
+    Countdown {
+        seconds: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    Shutdown,
+}
+
+lazy_static! {
+    static ref ID_RULE: Regex = Regex::new(r"^[A-Za-z0-9_-]{1,32}$").unwrap();
+}
+
+pub fn json_arg(v: Value) -> String {
+    match v {
+        Value::String(s) => s,
+        v => v.to_string(),
+    }
+}
+
+impl Proto {
+    /// Parse a request line into a command and its optional correlation id
+    pub fn decode(self, line: &str) -> RResult<(Command<'_>, Option<Value>)> {
+        match self {
+            Proto::Text => {
+                let (_, mut command) =
+                    parse_request(line).map_err(|e| SError::SyntaxError(e.to_string()))?;
+                let id = match command.args.remove("id") {
+                    Some(id) if ID_RULE.is_match(&id) => Some(Value::String(id)),
+                    Some(_) => {
+                        return Err(SError::SyntaxError(
+                            "id: 1-32 latin letters, digits, '-' or '_'".to_string(),
+                        ))
+                    }
+                    None => None,
+                };
+                Ok((command, id))
+            }
+            Proto::Json => {
+                let req: JsonRequest = serde_json::from_str(line)
+                    .map_err(|e| SError::SyntaxError(e.to_string()))?;
+                let command = Command {
+                    cmd: req.cmd,
+                    args: req
+                        .args
+                        .into_iter()
+                        .map(|(k, v)| (k, json_arg(v)))
+                        .collect(),
+                };
+                Ok((command, req.id))
+            }
+        }
+    }
+
+    /// Errors go with their code and a message in `lang`
+    pub fn encode_reply(
+        self,
+        lang: Lang,
+        id: Option<&Value>,
+        reply: &RResult<String>,
+    ) -> String {
+        match self {
+            Proto::Text => {
+                let status = if reply.is_ok() { SUCCESS } else { FAIL };
+                let id = match id {
+                    Some(Value::String(id)) => format!("{}{} ", ID_MARK, id),
+                    Some(id) => format!("{}{} ", ID_MARK, id),
+                    None => String::new(),
+                };
+                match reply {
+                    // "+#a b" must only ever be the reply to id "a"
+                    Ok(data) if id.is_empty() && data.starts_with(ID_MARK) => {
+                        format!("{}\\{}", status, escape(data))
+                    }
+                    Ok(data) => format!("{}{}{}", status, id, escape(data)),
+                    // some carry text from users, such as a ban reason
+                    Err(e) => format!("{}{}{} {}", status, id, e.code(), escape(&lang.describe(e))),
+                }
+            }
+            Proto::Json => to_json(&JsonOut::Response {
+                id,
+                ok: reply.is_ok(),
+                data: reply.as_ref().ok().map(String::as_str),
+                code: reply.as_ref().err().map(SError::code),
+                error: reply.as_ref().err().map(|e| lang.describe(e)),
+            }),
+        }
+    }
+
+    pub fn encode_push(self, push: &Push) -> String {
+        match self {
+            Proto::Text => match push {
+                Push::Msg {
+                    date,
+                    scope,
+                    from,
+                    msg,
+                } => {
+                    let from = match scope {
+                        Scope::Direct => from.clone(),
+                        Scope::All => format!("{}{}", from, TO_ALL),
+                        Scope::Room(room) => format!("{} (in {}{})", from, ROOM_MARK, room),
+                    };
+                    format!(
+                        "{}MSGFROM [{} {}] ({}): {}",
+                        PUSH,
+                        date,
+                        from,
+                        msg.chars().count(),
+                        escape(msg)
+                    )
+                }
+                Push::Timeout => format!("{}{}", PUSH, TIMEOUT_MSG),
+                Push::Countdown { seconds, reason } => format!(
+                    "{}{} in {} s{}",
+                    PUSH,
+                    SHUTDOWN_MSG,
+                    seconds,
+                    reason
+                        .as_ref()
+                        .map(|r| format!(": {}", escape(r)))
+                        .unwrap_or_default()
+                ),
+                Push::Shutdown => format!("{}{}", PUSH, SHUTDOWN_MSG),
+            },
+            Proto::Json => to_json(&match push {
+                Push::Msg {
+                    date,
+                    scope,
+                    from,
+                    msg,
+                } => JsonOut::Msg {
+                    date,
+                    room: match scope {
+                        Scope::Room(room) => Some(room),
+                        _ => None,
+                    },
+                    to_all: *scope == Scope::All,
+                    from,
+                    msg,
+                },
+                Push::Timeout => JsonOut::Timeout,
+                Push::Countdown { seconds, reason } => JsonOut::Countdown {
+                    seconds: *seconds,
+                    reason: reason.as_deref(),
+                },
+                Push::Shutdown => JsonOut::Shutdown,
+            }),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn to_json(out: &JsonOut) -> String {
+    serde_json::to_string(out).expect("can't serialize reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::unescape;
+
+    #[test]
+    fn test_json_decode() {
+        let line = r#"{"cmd":"MUTE","args":{"username":"foo","minutes":5},"id":"q1"}"#;
+        let (cmd, id) = Proto::Json.decode(line).unwrap();
+        assert_eq!(cmd.cmd, "MUTE");
+        assert_eq!(cmd.args["username"], "foo");
+        assert_eq!(cmd.args["minutes"], "5");
+        assert_eq!(id, Some(Value::from("q1")));
+        assert!(Proto::Json.decode("PING").is_err());
+
+        // escaped strings can't be borrowed from the line but still work
+        let line = r#"{"cmd":"\u0045CHO","args":{"m\u0073g":"hi","a\"b":1}}"#;
+        let (cmd, _) = Proto::Json.decode(line).unwrap();
+        assert_eq!(cmd.cmd, "ECHO");
+        assert_eq!(cmd.args["msg"], "hi");
+        assert_eq!(cmd.args["a\"b"], "1");
+    }
+
+    #[test]
+    fn test_text_id() {
+        let (cmd, id) = Proto::Text.decode("ECHO|msg=#x|id=req-1").unwrap();
+        assert!(!cmd.args.contains_key("id"));
+        let reply = Proto::Text.encode_reply(Lang::En, id.as_ref(), &Ok(cmd.args["msg"].clone()));
+        assert_eq!(reply, "+#req-1 #x");
+        let fail = Proto::Text.encode_reply(Lang::Ru, id.as_ref(), &Err(SError::DOS(5)));
+        assert_eq!(fail, "-#req-1 E429 Слишком часто, повторите через 5 мс");
+        // a ban reason comes from a moderator and mustn't forge a line
+        let banned = Err(SError::Banned("spam\n+#req-2 ok".to_string()));
+        let fail = Proto::Text.encode_reply(Lang::En, None, &banned);
+        assert!(!fail.contains('\n'));
+        assert!(unescape(&fail[1..]).unwrap().ends_with("spam\n+#req-2 ok"));
+        // data looking like an id is told apart from one
+        let data = Ok("#req-1 #x".to_string());
+        let reply = Proto::Text.encode_reply(Lang::En, None, &data);
+        assert_eq!(reply, "+\\#req-1 #x");
+        assert_eq!(unescape(&reply[1..]).as_deref(), Some("#req-1 #x"));
+        // handlers return raw data, escaped here for text only
+        let lines = Ok("a\nb|c".to_string());
+        let reply = Proto::Text.encode_reply(Lang::En, None, &lines);
+        assert_eq!(reply, "+a\\nb\\|c");
+        let json: Value =
+            serde_json::from_str(&Proto::Json.encode_reply(Lang::En, None, &lines)).unwrap();
+        assert_eq!(json["data"], "a\nb|c");
+        assert!(Proto::Text.decode("PING|id=no spaces").is_err());
+        assert_eq!(Proto::Text.encode_push(&Push::Timeout), "!TIMEOUT");
+    }
+
+    #[test]
+    fn test_json_encode() {
+        let id = Value::from(7);
+        let ok: Value =
+            serde_json::from_str(&Proto::Json.encode_reply(Lang::En, Some(&id), &Ok("pong".into())))
+                .unwrap();
+        assert_eq!(ok["type"], "response");
+        assert_eq!(ok["id"], 7);
+        assert_eq!(ok["ok"], true);
+        assert_eq!(ok["data"], "pong");
+        let err = Proto::Json.encode_reply(Lang::En, None, &Err(SError::NotLoggedIn));
+        let err: Value = serde_json::from_str(&err).unwrap();
+        assert_eq!(err["ok"], false);
+        assert_eq!(err["id"], Value::Null);
+        assert_eq!(err["code"], "E401");
+        assert_eq!(err["error"], "Please log in");
+        let push: Value = serde_json::from_str(&Proto::Json.encode_push(&Push::Shutdown)).unwrap();
+        assert_eq!(push["type"], "shutdown");
+        let countdown = Push::Countdown {
+            seconds: 30,
+            reason: Some("update|reboot".into()),
+        };
+        let push: Value = serde_json::from_str(&Proto::Json.encode_push(&countdown)).unwrap();
+        assert_eq!(push["type"], "countdown");
+        assert_eq!(push["seconds"], 30);
+        assert_eq!(
+            Proto::Text.encode_push(&countdown),
+            "!SHUTDOWN in 30 s: update\\|reboot"
+        );
+    }
+
+    #[test]
+    fn test_room_push() {
+        let push = Push::Msg {
+            date: "now".into(),
+            scope: Scope::Room("lab1".into()),
+            from: "foo".into(),
+            msg: "hi|all".into(),
+        };
+        assert_eq!(
+            Proto::Text.encode_push(&push),
+            "!MSGFROM [now foo (in #lab1)] (6): hi\\|all"
+        );
+        let json: Value = serde_json::from_str(&Proto::Json.encode_push(&push)).unwrap();
+        assert_eq!(json["room"], "lab1");
+        assert_eq!(json["from"], "foo");
+        assert!(json.get("to_all").is_none());
+
+        let push = Push::Msg {
+            date: "now".into(),
+            scope: Scope::All,
+            from: "foo".into(),
+            msg: "hi".into(),
+        };
+        assert_eq!(
+            Proto::Text.encode_push(&push),
+            "!MSGFROM [now foo (to all)] (2): hi"
+        );
+        let json: Value = serde_json::from_str(&Proto::Json.encode_push(&push)).unwrap();
+        assert_eq!(json["to_all"], true);
+        assert_eq!(json["from"], "foo");
+    }
+}
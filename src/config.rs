@@ -1,12 +1,556 @@
-pub const DB_PATH: &str = "users.json";
-pub const LOGFILE: &str = "pi_server.log";
-pub const PORT: &str = "81";
-pub const CMD_BUF_SIZE: usize = 256;
-pub const SILENT_CONN_TIMEOUT: usize = 40;
-pub const HALT_MS: u64 = 50;
+use clap::Parser;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    api::{self, LOGIN_RULE},
+    error::ConfigError,
+    ratelimit::COSTS,
+};
+
+pub const HISTORY_LIMIT: usize = 20;
+pub const HISTORY_MAX_LIMIT: usize = 100;
+pub const MAX_ROOMS: usize = 16;
+pub const MAX_MUTE_MINUTES: u64 = 60 * 24 * 365;
+pub const MAX_SHUTDOWN_SECONDS: u64 = 60 * 60;
+pub const MAX_QUEUE_TTL_HOURS: u64 = 24 * 365;
 pub const SUCCESS: &str = "+";
 pub const FAIL: &str = "-";
+pub const PUSH: &str = "!";
+pub const ID_MARK: &str = "#";
 pub const ONLINE: &str = "*";
-pub const ADMIN: &str = "ортём";
+pub const ROOM_MARK: &str = "#";
+/// Appended to the sender of a message to everyone
+pub const TO_ALL: &str = " (to all)";
 pub const SHUTDOWN_MSG: &str = "SHUTDOWN";
 pub const TIMEOUT_MSG: &str = "TIMEOUT";
+pub const DEFAULT_CONFIG_PATH: &str = "pi_server.toml";
+/// Settings a reload leaves alone, they only take effect on start
+const RESTART_ONLY: &[&str] = &[
+    "bind",
+    "port",
+    "daemon",
+    "log",
+    "storage",
+    "db",
+    "history",
+    "bans",
+    "tls_port",
+    "tls_cert",
+    "tls_key",
+    "ws_port",
+    "http_port",
+    "irc_port",
+];
+
+/// Settings that may differ between instances running on the same board.
+/// Defaults are overridden by the config file, which is overridden by the
+/// command line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub daemon: bool,
+    pub log: String,
+    /// off, error, warn, info, debug or trace
+    pub log_level: String,
+    /// Storage backend: json or sqlite
+    pub storage: String,
+    /// Users db, the default depends on the backend
+    pub db: Option<String>,
+    /// Message history of the json backend
+    pub history: String,
+    /// Banned addresses of the json backend
+    pub bans: String,
+    pub admin: String,
+    /// Max request length in bytes
+    pub max_line_len: usize,
+    /// Bytes read from a socket at once
+    pub read_buf_size: usize,
+    /// Seconds of silence before a client gets TIMEOUT
+    pub silent_timeout: u64,
+    /// Command tokens a user gets per second
+    pub rate: f64,
+    /// How many tokens a user can save up
+    pub burst: f64,
+    /// Connections open at once from one address
+    pub max_conns_per_ip: usize,
+    /// New connections per second from one address
+    pub conn_rate: f64,
+    pub conn_burst: f64,
+    /// Seconds between periodic db saves
+    pub autosave_interval: u64,
+    /// Delay before saving the db after a change
+    pub autosave_delay_ms: u64,
+    /// Messages held for a user until they are delivered
+    pub queue_max: usize,
+    /// Hours an undelivered message is held, 0 - until delivered
+    pub queue_ttl_hours: u64,
+    /// What a full queue does with a new message: drop_oldest or reject
+    pub queue_overflow: String,
+    /// Port for TLS connections, besides the plaintext one
+    pub tls_port: Option<u16>,
+    /// PEM certificate chain for the TLS port
+    pub tls_cert: Option<String>,
+    /// PEM private key for the TLS port
+    pub tls_key: Option<String>,
+    /// Port for WebSocket connections
+    pub ws_port: Option<u16>,
+    /// Port of the HTTP API
+    pub http_port: Option<u16>,
+    /// Port for IRC clients
+    pub irc_port: Option<u16>,
+    /// Greeting sent in reply to LOGIN
+    pub motd: Option<String>,
+    /// Tokens taken by each command, the rest take 1
+    #[serde(deserialize_with = "over_default_costs")]
+    pub costs: BTreeMap<String, f64>,
+}
+
+fn default_costs() -> BTreeMap<String, f64> {
+    COSTS
+        .iter()
+        .map(|(cmd, cost)| (cmd.to_string(), *cost))
+        .collect()
+}
+
+/// Costs from the config file are added to the built-in ones
+fn over_default_costs<'de, D: Deserializer<'de>>(d: D) -> Result<BTreeMap<String, f64>, D::Error> {
+    let mut costs = default_costs();
+    let overrides = BTreeMap::<String, f64>::deserialize(d)?;
+    costs.extend(
+        overrides
+            .into_iter()
+            .map(|(cmd, cost)| (cmd.to_uppercase(), cost)),
+    );
+    Ok(costs)
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            bind: [0, 0, 0, 0].into(),
+            port: 81,
+            daemon: false,
+            log: "pi_server.log".to_string(),
+            log_level: "debug".to_string(),
+            storage: "json".to_string(),
+            db: None,
+            history: "history.jsonl".to_string(),
+            bans: "bans.json".to_string(),
+            admin: "ортём".to_string(),
+            max_line_len: 256,
+            read_buf_size: 4096,
+            silent_timeout: 40,
+            rate: 2.0,
+            burst: 10.0,
+            max_conns_per_ip: 16,
+            conn_rate: 1.0,
+            conn_burst: 10.0,
+            autosave_interval: 60,
+            autosave_delay_ms: 1000,
+            queue_max: 100,
+            queue_ttl_hours: 72,
+            queue_overflow: "drop_oldest".to_string(),
+            tls_port: None,
+            tls_cert: None,
+            tls_key: None,
+            ws_port: None,
+            http_port: None,
+            irc_port: None,
+            motd: None,
+            costs: default_costs(),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Default, Clone)]
+#[command(version, about = "Chat server")]
+pub struct Cli {
+    /// Config file [default: pi_server.toml, if it exists]
+    #[arg(short, long)]
+    pub config: Option<String>,
+    /// Port to listen on
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Address to listen on
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Storage backend: json or sqlite
+    #[arg(long)]
+    pub storage: Option<String>,
+    /// Users db path
+    #[arg(long)]
+    pub db: Option<String>,
+    /// Log file path
+    #[arg(long)]
+    pub log: Option<String>,
+    /// Fork to background
+    #[arg(short, long)]
+    pub daemon: bool,
+    /// Login of the administrator
+    #[arg(long)]
+    pub admin: Option<String>,
+    /// Port for TLS connections
+    #[arg(long)]
+    pub tls_port: Option<u16>,
+    /// PEM certificate chain for the TLS port
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// PEM private key for the TLS port
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// Port for WebSocket connections
+    #[arg(long)]
+    pub ws_port: Option<u16>,
+    /// Port of the HTTP API
+    #[arg(long)]
+    pub http_port: Option<u16>,
+    /// Port for IRC clients
+    #[arg(long)]
+    pub irc_port: Option<u16>,
+    /// Print the resulting config and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+impl Settings {
+    /// Read the config file chosen by `cli` and apply the flags on top
+    pub fn load(cli: &Cli) -> Result<Settings, ConfigError> {
+        let path = cli.config.as_deref().unwrap_or(DEFAULT_CONFIG_PATH);
+        let mut settings = match fs::read_to_string(path) {
+            Ok(text) => Settings::parse(&text).map_err(|e| match e {
+                ConfigError::Parse(_, e) => ConfigError::Parse(path.to_string(), e),
+                e => e,
+            })?,
+            // only an explicitly requested config has to exist
+            Err(e) if e.kind() == io::ErrorKind::NotFound && cli.config.is_none() => {
+                Settings::default()
+            }
+            Err(e) => return Err(ConfigError::Io(path.to_string(), e)),
+        };
+        settings.apply(cli);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn parse(text: &str) -> Result<Settings, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError::Parse(String::new(), e))
+    }
+
+    fn apply(&mut self, cli: &Cli) {
+        if let Some(port) = cli.port {
+            self.port = port;
+        }
+        if let Some(bind) = cli.bind {
+            self.bind = bind;
+        }
+        if let Some(storage) = cli.storage.as_ref() {
+            self.storage = storage.clone();
+        }
+        if let Some(db) = cli.db.as_ref() {
+            self.db = Some(db.clone());
+        }
+        if let Some(log) = cli.log.as_ref() {
+            self.log = log.clone();
+        }
+        if let Some(admin) = cli.admin.as_ref() {
+            self.admin = admin.clone();
+        }
+        if let Some(port) = cli.tls_port {
+            self.tls_port = Some(port);
+        }
+        if let Some(cert) = cli.tls_cert.as_ref() {
+            self.tls_cert = Some(cert.clone());
+        }
+        if let Some(key) = cli.tls_key.as_ref() {
+            self.tls_key = Some(key.clone());
+        }
+        if let Some(port) = cli.ws_port {
+            self.ws_port = Some(port);
+        }
+        if let Some(port) = cli.http_port {
+            self.http_port = Some(port);
+        }
+        if let Some(port) = cli.irc_port {
+            self.irc_port = Some(port);
+        }
+        self.daemon |= cli.daemon;
+        if self.db.is_none() {
+            self.db = Some(self.db_path().to_string());
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid =
+            |field: &str, why: &str| Err(ConfigError::Invalid(format!("{}: {}", field, why)));
+        if self.port == 0 {
+            return invalid("port", "must be 1..65535");
+        }
+        match (self.tls_port, &self.tls_cert, &self.tls_key) {
+            (None, None, None) => (),
+            (Some(0), _, _) => return invalid("tls_port", "must be 1..65535"),
+            (Some(port), _, _) if port == self.port => {
+                return invalid("tls_port", "must differ from port")
+            }
+            (Some(_), Some(_), Some(_)) => (),
+            _ => return invalid("tls_port/tls_cert/tls_key", "must be set together"),
+        }
+        let extra_ports = [
+            ("ws_port", self.ws_port),
+            ("http_port", self.http_port),
+            ("irc_port", self.irc_port),
+        ];
+        let mut taken = vec![Some(self.port), self.tls_port];
+        for (field, port) in extra_ports.iter() {
+            match port {
+                Some(0) => return invalid(field, "must be 1..65535"),
+                Some(_) if taken.contains(port) => {
+                    return invalid(field, "must differ from the other ports")
+                }
+                _ => taken.push(*port),
+            }
+        }
+        if !["json", "sqlite"].contains(&self.storage.as_str()) {
+            return invalid("storage", "must be json or sqlite");
+        }
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return invalid(
+                "log_level",
+                "must be off, error, warn, info, debug or trace",
+            );
+        }
+        if !LOGIN_RULE.is_match(&self.admin) {
+            return invalid("admin", "not a valid login");
+        }
+        if self.max_line_len < 16 {
+            return invalid("max_line_len", "must be at least 16");
+        }
+        if self.read_buf_size < 64 {
+            return invalid("read_buf_size", "must be at least 64");
+        }
+        if self.silent_timeout == 0 {
+            return invalid("silent_timeout", "must be positive");
+        }
+        if self.autosave_interval == 0 {
+            return invalid("autosave_interval", "must be positive");
+        }
+        if !(self.rate > 0.0 && self.burst >= 1.0) {
+            return invalid("rate/burst", "rate must be positive and burst at least 1");
+        }
+        if !(self.conn_rate > 0.0 && self.conn_burst >= 1.0) {
+            return invalid(
+                "conn_rate/conn_burst",
+                "rate must be positive and burst at least 1",
+            );
+        }
+        if self.max_conns_per_ip == 0 {
+            return invalid("max_conns_per_ip", "must be positive");
+        }
+        if self.queue_max == 0 {
+            return invalid("queue_max", "must be positive");
+        }
+        if self.queue_ttl_hours > MAX_QUEUE_TTL_HOURS {
+            let range = format!("must be 0..{}", MAX_QUEUE_TTL_HOURS);
+            return invalid("queue_ttl_hours", &range);
+        }
+        if !["drop_oldest", "reject"].contains(&self.queue_overflow.as_str()) {
+            return invalid("queue_overflow", "must be drop_oldest or reject");
+        }
+        if let Some(cmd) = self.costs.keys().find(|cmd| !api::is_command(cmd)) {
+            return invalid("costs", &format!("no such command {}", cmd));
+        }
+        // a command costing more than the burst could never run
+        if let Some((cmd, _)) = self
+            .costs
+            .iter()
+            .find(|(_, cost)| !(**cost >= 0.0 && **cost <= self.burst))
+        {
+            return invalid("costs", &format!("{} must cost 0..burst", cmd));
+        }
+        Ok(())
+    }
+
+    pub fn db_path(&self) -> &str {
+        match (self.db.as_deref(), self.storage.as_str()) {
+            (Some(db), _) => db,
+            (None, "sqlite") => "pi_server.sqlite",
+            (None, _) => "users.json",
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("can't serialize config")
+    }
+
+    pub fn log_filter(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::Debug)
+    }
+
+    /// `new` with the restart-only settings kept as they are now, and
+    /// what has changed, as `key: old -> new`
+    fn merge(&self, new: Settings) -> Result<(Settings, Vec<String>), ConfigError> {
+        let table = |s: &Settings| match toml::Value::try_from(s) {
+            Ok(toml::Value::Table(t)) => t,
+            _ => unreachable!("settings are a table"),
+        };
+        let show = |v: Option<&toml::Value>| v.map_or("none".to_string(), toml::Value::to_string);
+        let (old, mut merged) = (table(self), table(&new));
+        let keys = old
+            .keys()
+            .chain(merged.keys())
+            .cloned()
+            .collect::<BTreeSet<String>>();
+        let mut changes = vec![];
+        for key in keys {
+            let (was, now) = (old.get(&key), merged.get(&key));
+            if was == now {
+                continue;
+            }
+            if RESTART_ONLY.contains(&key.as_str()) {
+                changes.push(format!(
+                    "{}: {} -> {} (needs a restart)",
+                    key,
+                    show(was),
+                    show(now)
+                ));
+                match was {
+                    Some(was) => merged.insert(key, was.clone()),
+                    None => merged.remove(&key),
+                };
+                continue;
+            }
+            // tables such as costs are told apart by their keys
+            match (
+                was.and_then(toml::Value::as_table),
+                now.and_then(toml::Value::as_table),
+            ) {
+                (Some(was), Some(now)) => {
+                    let subkeys = was.keys().chain(now.keys()).collect::<BTreeSet<&String>>();
+                    for sub in subkeys {
+                        if was.get(sub) != now.get(sub) {
+                            let (a, b) = (show(was.get(sub)), show(now.get(sub)));
+                            changes.push(format!("{}.{}: {} -> {}", key, sub, a, b));
+                        }
+                    }
+                }
+                _ => changes.push(format!("{}: {} -> {}", key, show(was), show(now))),
+            }
+        }
+        let merged = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| ConfigError::Parse(String::new(), e))?;
+        Ok((merged, changes))
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
+    /// Flags the server was started with, they stay on top of reloaded files
+    static ref CLI: RwLock<Cli> = RwLock::new(Cli::default());
+}
+
+/// Settings currently in effect
+pub fn settings() -> Arc<Settings> {
+    SETTINGS.read().unwrap().clone()
+}
+
+pub fn set_settings(settings: Settings) {
+    *SETTINGS.write().unwrap() = Arc::new(settings);
+}
+
+pub fn set_cli(cli: Cli) {
+    *CLI.write().unwrap() = cli;
+}
+
+/// Read the config file again and apply what can change at runtime.
+/// Returns the changes, an invalid config leaves everything as it was.
+pub fn reload() -> Result<Vec<String>, ConfigError> {
+    let new = Settings::load(&CLI.read().unwrap())?;
+    let (merged, changes) = settings().merge(new)?;
+    log::set_max_level(merged.log_filter());
+    set_settings(merged);
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_and_flags() {
+        let text = "port = 8081\nstorage = \"sqlite\"\n[costs]\nsend = 0.5\n";
+        let mut settings = Settings::parse(text).unwrap();
+        assert_eq!(settings.port, 8081);
+        assert_eq!(settings.costs["SEND"], 0.5);
+        assert_eq!(
+            settings.costs["SNDALL"],
+            Settings::default().costs["SNDALL"]
+        );
+        assert_eq!(settings.silent_timeout, Settings::default().silent_timeout);
+
+        let cli = Cli::parse_from(["pi_server", "--port", "9000", "-d", "--admin", "root"]);
+        settings.apply(&cli);
+        assert_eq!(settings.port, 9000);
+        assert_eq!(settings.db.as_deref(), Some("pi_server.sqlite"));
+        assert!(settings.daemon);
+        settings.validate().unwrap();
+        assert_eq!(Settings::parse(&settings.to_toml()).unwrap(), settings);
+    }
+
+    #[test]
+    fn test_merge() {
+        let old = Settings::default();
+        let new = Settings::parse(
+            "port = 9000\nrate = 4.0\nmotd = \"hi\"\nlog_level = \"info\"\n[costs]\nsend = 0.5",
+        )
+        .unwrap();
+        let (merged, changes) = old.merge(new).unwrap();
+        assert_eq!(merged.port, old.port);
+        assert_eq!(merged.rate, 4.0);
+        assert_eq!(merged.motd.as_deref(), Some("hi"));
+        assert_eq!(merged.costs["SEND"], 0.5);
+        assert_eq!(merged.log_filter(), LevelFilter::Info);
+        assert!(changes.contains(&"port: 81 -> 9000 (needs a restart)".to_string()));
+        assert!(changes.contains(&"motd: none -> \"hi\"".to_string()));
+        assert!(changes.iter().any(|c| c.starts_with("costs.SEND: ")));
+        assert_eq!(changes.len(), 5, "{:?}", changes);
+        assert!(old.merge(old.clone()).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Settings::parse("prot = 81").is_err());
+        assert!(Settings::parse("port = 100000").is_err());
+        for text in &[
+            "burst = 0.5",
+            "[costs]\nping = 100",
+            "[costs]\nsned = 1",
+            "port = 0",
+            "admin = \"a:b\"",
+            "storage = \"csv\"",
+            "silent_timeout = 0",
+            "tls_port = 8443",
+            "ws_port = 81",
+            "ws_port = 8081\nhttp_port = 8081",
+            "tls_port = 81\ntls_cert = \"c.pem\"\ntls_key = \"k.pem\"",
+            "log_level = \"loud\"",
+            "queue_max = 0",
+            "queue_ttl_hours = 9000000000000000",
+            "queue_overflow = \"block\"",
+        ] {
+            assert!(
+                Settings::parse(text).unwrap().validate().is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
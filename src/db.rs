@@ -1,22 +1,190 @@
-use crate::{api::RResult, client::CliTask, config::*, error::SError};
-use std::fs::{File, OpenOptions};
-use std::net::SocketAddr;
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use std::time::SystemTime;
-
-use serde::{Deserialize, Serialize};
-use serde_json;
+use crate::{
+    api::RResult,
+    auth::{hash_password, hash_token, looks_hashed, new_token, verify_password, Role, Verdict},
+    client::CliTask,
+    config::*,
+    error::SError,
+    history::{HistoryQuery, Message},
+    server,
+    storage::Storage,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::Local;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// How many jobs a client may have queued and for how long
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLimits {
+    pub max: usize,
+    /// Jobs older than this are dropped undelivered
+    pub ttl: Option<Duration>,
+    /// Refuse new jobs for a full queue instead of dropping the oldest one
+    pub reject: bool,
+}
+
+impl QueueLimits {
+    fn current() -> QueueLimits {
+        let settings = settings();
+        QueueLimits {
+            max: settings.queue_max,
+            ttl: match settings.queue_ttl_hours {
+                0 => None,
+                hours => Some(Duration::from_secs(hours * 60 * 60)),
+            },
+            reject: settings.queue_overflow == "reject",
+        }
+    }
+
+    fn expired(&self, job: &Queued, now: SystemTime) -> bool {
+        match (self.ttl, now.duration_since(job.at)) {
+            (Some(ttl), Ok(age)) => age > ttl,
+            _ => false,
+        }
+    }
+}
+
+/// A job and when it was queued
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Queued {
+    task: CliTask,
+    at: SystemTime,
+}
+
+/// Records saved before jobs had a date hold bare tasks
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredJob {
+    Queued(Queued),
+    Bare(CliTask),
+}
+
+/// Jobs of a single client, lockable without write-locking the whole db
+#[derive(Default, Debug)]
+pub struct JobQueue(Mutex<Vec<Queued>>);
+
+impl JobQueue {
+    /// Queue `task` within `limits`, returns false if it was refused
+    fn push(&self, task: CliTask, limits: &QueueLimits) -> bool {
+        let mut jobs = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        jobs.retain(|job| !limits.expired(job, now));
+        // EXIT is the client's own request, not a message to hold back
+        if jobs.len() >= limits.max && !matches!(task, CliTask::Exit) {
+            if limits.reject {
+                return false;
+            }
+            let extra = jobs.len() + 1 - limits.max;
+            jobs.drain(..extra);
+        }
+        jobs.push(Queued { task, at: now });
+        true
+    }
+
+    fn drain(&self, limits: &QueueLimits) -> Vec<CliTask> {
+        let now = SystemTime::now();
+        self.0
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|job| !limits.expired(job, now))
+            .map(|job| job.task)
+            .collect()
+    }
+
+    /// Drop the jobs past their TTL, returns how many
+    fn expire(&self, limits: &QueueLimits) -> usize {
+        let mut jobs = self.0.lock().unwrap();
+        let (before, now) = (jobs.len(), SystemTime::now());
+        jobs.retain(|job| !limits.expired(job, now));
+        before - jobs.len()
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+impl Clone for JobQueue {
+    fn clone(&self) -> JobQueue {
+        JobQueue(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl Serialize for JobQueue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.lock().unwrap().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for JobQueue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JobQueue, D::Error> {
+        let now = SystemTime::now();
+        let jobs = Vec::<StoredJob>::deserialize(deserializer)?
+            .into_iter()
+            .map(|job| match job {
+                StoredJob::Queued(job) => job,
+                StoredJob::Bare(task) => Queued { task, at: now },
+            })
+            .collect();
+        Ok(JobQueue(Mutex::new(jobs)))
+    }
+}
+
+/// Who banned someone, when and why
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub by: String,
+    pub reason: String,
+    pub date: String,
+}
+
+impl Ban {
+    pub fn new(by: String, reason: String) -> Ban {
+        Ban {
+            by,
+            reason,
+            date: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// Connections from `addr` are dropped right after accept
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IpBan {
+    pub addr: IpAddr,
+    #[serde(flatten)]
+    pub ban: Ban,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CliData {
     addr: SocketAddr,
     uid: Uuid,
-    jobs: Vec<CliTask>,
+    jobs: JobQueue,
     login: Option<String>,
-    last_cmd_ts: SystemTime,
     password: Option<String>,
+    /// Whether `password` is a hash, None in records saved before this
+    /// was stored, see `ClientDB::load`
+    #[serde(default)]
+    password_hashed: Option<bool>,
     online: bool,
+    #[serde(default)]
+    rooms: BTreeSet<String>,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    muted_until: Option<SystemTime>,
+    #[serde(default)]
+    ban: Option<Ban>,
+    /// Hash of the API token
+    #[serde(default)]
+    api_token: Option<String>,
 }
 
 impl Default for CliData {
@@ -24,29 +192,233 @@ impl Default for CliData {
         CliData {
             addr: "127.0.0.1:31337".parse().unwrap(),
             uid: Uuid::new_v4(),
-            jobs: vec![],
+            jobs: JobQueue::default(),
             login: None,
-            last_cmd_ts: SystemTime::now(),
             password: None,
+            password_hashed: None,
             online: false,
+            rooms: BTreeSet::new(),
+            role: Role::User,
+            muted_until: None,
+            ban: None,
+            api_token: None,
+        }
+    }
+}
+
+impl CliData {
+    pub fn uid(&self) -> Uuid {
+        self.uid
+    }
+
+    pub fn login(&self) -> Option<&str> {
+        self.login.as_deref()
+    }
+
+    #[cfg(test)]
+    pub fn jobs(&self) -> Vec<CliTask> {
+        let jobs = self.jobs.0.lock().unwrap();
+        jobs.iter().map(|job| job.task.clone()).collect()
+    }
+
+    #[cfg(test)]
+    pub fn named(login: &str) -> CliData {
+        CliData {
+            login: Some(login.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn push_job(&mut self, task: CliTask) {
+        let limits = QueueLimits {
+            max: usize::MAX,
+            ttl: None,
+            reject: false,
+        };
+        self.jobs.push(task, &limits);
+    }
+}
+
+/// All known clients, connected or not, indexed by uid, by login, by API
+/// token and by the rooms they are in. A room exists while it has at least
+/// one member.
+#[derive(Default)]
+pub struct Store {
+    clients: HashMap<Uuid, CliData>,
+    logins: HashMap<String, Uuid>,
+    api_tokens: HashMap<String, Uuid>,
+    rooms: HashMap<String, HashSet<Uuid>>,
+    ip_bans: HashMap<IpAddr, Ban>,
+    /// Bans (Some) and unbans (None) not saved yet, a reload keeps them
+    unsaved_ip_bans: HashMap<IpAddr, Option<Ban>>,
+}
+
+impl Store {
+    fn new(clients: Vec<CliData>) -> Store {
+        let mut store = Store::default();
+        clients.into_iter().for_each(|cli| store.insert(cli));
+        store
+    }
+
+    fn insert(&mut self, cli: CliData) {
+        if let Some(login) = cli.login.as_ref() {
+            self.logins.insert(login.clone(), cli.uid);
+        }
+        if let Some(token) = cli.api_token.as_ref() {
+            self.api_tokens.insert(token.clone(), cli.uid);
+        }
+        for room in cli.rooms.iter() {
+            self.rooms.entry(room.clone()).or_default().insert(cli.uid);
+        }
+        self.clients.insert(cli.uid, cli);
+    }
+
+    fn remove(&mut self, uid: Uuid) -> Option<CliData> {
+        let cli = self.clients.remove(&uid)?;
+        if let Some(login) = cli.login.as_ref() {
+            self.logins.remove(login);
+        }
+        if let Some(token) = cli.api_token.as_ref() {
+            self.api_tokens.remove(token);
+        }
+        for room in cli.rooms.iter() {
+            self.unindex_member(room, uid);
+        }
+        Some(cli)
+    }
+
+    fn unindex_member(&mut self, room: &str, uid: Uuid) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&uid);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Returns false if `uid` is unknown or already in `room`
+    fn join(&mut self, uid: Uuid, room: &str) -> bool {
+        let joined = match self.clients.get_mut(&uid) {
+            Some(cli) => cli.rooms.insert(room.to_string()),
+            None => false,
+        };
+        if joined {
+            self.rooms.entry(room.to_string()).or_default().insert(uid);
+        }
+        joined
+    }
+
+    /// Returns false if `uid` is unknown or not in `room`
+    fn part(&mut self, uid: Uuid, room: &str) -> bool {
+        let parted = match self.clients.get_mut(&uid) {
+            Some(cli) => cli.rooms.remove(room),
+            None => false,
+        };
+        if parted {
+            self.unindex_member(room, uid);
+        }
+        parted
+    }
+
+    fn members(&self, room: &str) -> Option<impl Iterator<Item = &CliData>> {
+        let members = self.rooms.get(room)?;
+        Some(members.iter().filter_map(move |uid| self.clients.get(uid)))
+    }
+
+    fn get(&self, uid: Uuid) -> Option<&CliData> {
+        self.clients.get(&uid)
+    }
+
+    fn get_mut(&mut self, uid: Uuid) -> Option<&mut CliData> {
+        self.clients.get_mut(&uid)
+    }
+
+    fn by_login(&self, login: &str) -> Option<&CliData> {
+        self.logins.get(login).and_then(|uid| self.clients.get(uid))
+    }
+
+    fn set_login(&mut self, uid: Uuid, login: String) {
+        if let Some(cli) = self.clients.get_mut(&uid) {
+            if let Some(old) = cli.login.replace(login.clone()) {
+                self.logins.remove(&old);
+            }
+            self.logins.insert(login, uid);
+        }
+    }
+
+    /// Replace the API token hash of `uid`, returns false if `uid` is unknown
+    fn set_token(&mut self, uid: Uuid, token: Option<String>) -> bool {
+        let cli = match self.clients.get_mut(&uid) {
+            Some(cli) => cli,
+            None => return false,
+        };
+        if let Some(old) = std::mem::replace(&mut cli.api_token, token.clone()) {
+            self.api_tokens.remove(&old);
+        }
+        if let Some(token) = token {
+            self.api_tokens.insert(token, uid);
         }
+        true
     }
+
+    /// Connected clients with an address from `ip`
+    fn connected_from(&self, ip: IpAddr) -> Vec<Uuid> {
+        self.iter()
+            .filter(|cli| cli.online && cli.addr.ip() == ip)
+            .map(|cli| cli.uid)
+            .collect()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &CliData> {
+        self.clients.values()
+    }
+
+    fn push_job(&self, uid: Uuid, task: CliTask, limits: &QueueLimits) -> RResult<()> {
+        match self.clients.get(&uid) {
+            Some(cli) if cli.jobs.push(task, limits) => Ok(()),
+            Some(_) => Err(SError::QueueFull(limits.max)),
+            None => Err(SError::NoSuchUser),
+        }
+    }
+
+    /// Queue `task` for every member of `room`, returns who got it
+    fn room_cast(&self, room: &str, task: &CliTask, limits: &QueueLimits) -> Cast {
+        cast(self.members(room).into_iter().flatten(), task, limits)
+    }
+
+    /// Queue `task` for every account
+    fn broadcast(&self, task: &CliTask, limits: &QueueLimits) -> Cast {
+        cast(self.iter().filter(|cli| cli.login.is_some()), task, limits)
+    }
+
+    /// Drop the queued jobs past their TTL, returns how many
+    fn expire_jobs(&self, limits: &QueueLimits) -> usize {
+        self.iter().map(|cli| cli.jobs.expire(limits)).sum()
+    }
+}
+
+/// Who got a job queued for many, and how many full queues refused it
+type Cast = (Vec<Uuid>, usize);
+
+fn cast<'a, I>(clients: I, task: &CliTask, limits: &QueueLimits) -> Cast
+where
+    I: Iterator<Item = &'a CliData>,
+{
+    let (queued, refused): (Vec<&CliData>, Vec<&CliData>) =
+        clients.partition(|cli| cli.jobs.push(task.clone(), limits));
+    (queued.iter().map(|cli| cli.uid).collect(), refused.len())
 }
 
-type CDB = Vec<CliData>;
+type CDB = Store;
 
 lazy_static! {
-    static ref DB: RwLock<CDB> = RwLock::new({
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(DB_PATH)
-            .unwrap();
-        let mut db: Vec<CliData> = serde_json::from_reader(file).unwrap_or(vec![]);
-        db.iter_mut().for_each(|cli| cli.online = false);
-        db
-    });
+    static ref DB: RwLock<CDB> = RwLock::new(Store::default());
+    // set when something worth persisting has changed
+    static ref DIRTY: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+    // the autosave thread and signal handlers must not write the file at once
+    static ref SYNC_LOCK: Mutex<()> = Mutex::new(());
+    static ref STORAGE: RwLock<Option<Box<dyn Storage>>> = RwLock::new(None);
 }
 
 pub struct ClientDB;
@@ -60,6 +432,8 @@ impl ClientDB {
         DB.write().unwrap()
     }
 
+    /// Connected client by its address. The only lookup without an index,
+    /// admins use it rarely to kick anonymous connections.
     pub fn get_uid(addr: &SocketAddr) -> Option<Uuid> {
         Self::_lock_read()
             .iter()
@@ -67,69 +441,156 @@ impl ClientDB {
             .map(|c| c.uid)
     }
 
-    pub fn update_cmd_ts(uid: Uuid) {
-        Self::_lock_write()
-            .iter_mut()
-            .find(|cli| cli.uid == uid)
-            .unwrap()
-            .last_cmd_ts = SystemTime::now();
+    /// Start every test from the same empty in-memory db
+    #[cfg(test)]
+    pub fn init_test_db() {
+        use crate::storage::MemoryStorage;
+        use std::sync::Once;
+
+        static INIT: Once = Once::new();
+        INIT.call_once(|| Self::load(Box::new(MemoryStorage::default())).unwrap());
     }
 
-    pub fn check_cmd_timeout(uid: Uuid) -> RResult<()> {
-        let last_cmd_ts: SystemTime = Self::_lock_read()
-            .iter()
-            .find(|cli| cli.uid == uid)
-            .unwrap()
-            .last_cmd_ts;
-        if last_cmd_ts.elapsed().unwrap().as_millis() < 500 {
-            return Err(SError::DOS);
-        } else {
-            Self::update_cmd_ts(uid);
-            return Ok(());
+    pub fn load(storage: Box<dyn Storage>) -> Result<(), String> {
+        let describe = |e| format!("{} ({})", e, storage.describe());
+        let mut db = storage.load().map_err(describe)?;
+        let ip_bans = storage.load_ip_bans().map_err(describe)?;
+        for cli in db.iter_mut() {
+            cli.online = false;
+            if cli.password_hashed.is_none() {
+                let stored = cli.password.as_deref().unwrap_or_default();
+                cli.password_hashed = Some(looks_hashed(stored));
+            }
         }
+        info!(
+            "Loaded {} users and {} banned addresses from {}",
+            db.len(),
+            ip_bans.len(),
+            storage.describe()
+        );
+        let mut store = Store::new(db);
+        store.ip_bans = ip_bans.into_iter().map(|b| (b.addr, b.ban)).collect();
+        *Self::_lock_write() = store;
+        *STORAGE.write().unwrap() = Some(storage);
+        Ok(())
     }
 
     pub fn sync_db() {
-        if let Err(e) = serde_json::to_writer(
-            File::create(DB_PATH).unwrap(),
-            &Self::_lock_read()
+        let _sync = SYNC_LOCK.lock().unwrap();
+        let (users, ip_bans, unsaved) = {
+            let db = Self::_lock_read();
+            let expired = db.expire_jobs(&QueueLimits::current());
+            if expired > 0 {
+                info!("Dropped {} queued messages past their TTL", expired);
+            }
+            let users = db
                 .iter()
                 .filter(|cli| cli.login.is_some())
-                .collect::<Vec<&CliData>>(),
-        ) {
-            error!("Failed to dump db: {}", e);
+                .cloned()
+                .collect::<Vec<CliData>>();
+            let ip_bans = db
+                .ip_bans
+                .iter()
+                .map(|(addr, ban)| IpBan {
+                    addr: *addr,
+                    ban: ban.clone(),
+                })
+                .collect::<Vec<IpBan>>();
+            (users, ip_bans, db.unsaved_ip_bans.clone())
+        };
+        if let Some(storage) = STORAGE.read().unwrap().as_ref() {
+            let saved = storage
+                .save(&users)
+                .and_then(|_| storage.save_ip_bans(&ip_bans))
+                .and_then(|_| storage.flush());
+            match saved {
+                // keeping whatever has changed again since the snapshot
+                Ok(()) => Self::_lock_write()
+                    .unsaved_ip_bans
+                    .retain(|addr, ban| unsaved.get(addr) != Some(ban)),
+                Err(e) => error!("Failed to dump db to {}: {}", storage.describe(), e),
+            }
         }
     }
 
+    /// Append a delivered message to the history. A failure here shouldn't
+    /// undo the delivery, so it's only logged.
+    pub fn log_message(msg: Message) {
+        if let Some(storage) = STORAGE.read().unwrap().as_ref() {
+            if let Err(e) = storage.append_message(msg) {
+                error!("Failed to save message to {}: {}", storage.describe(), e);
+            }
+        }
+    }
+
+    /// Drop the direct messages of a deleted user from the history
+    pub fn forget_history(login: &str) {
+        if let Some(storage) = STORAGE.read().unwrap().as_ref() {
+            match storage.forget_user(login) {
+                Ok(0) => {}
+                Ok(n) => info!("Dropped {} direct messages of {}", n, login),
+                Err(e) => error!("Failed to drop messages of {}: {}", login, e),
+            }
+        }
+    }
+
+    pub fn history(query: &HistoryQuery) -> RResult<Vec<Message>> {
+        match STORAGE.read().unwrap().as_ref() {
+            Some(storage) => storage.history(query).map_err(|e| {
+                error!("Failed to read history from {}: {}", storage.describe(), e);
+                SError::Internal
+            }),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn mark_dirty() {
+        let (dirty, cvar) = &*DIRTY;
+        *dirty.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+
+    /// Save the db shortly after every change and periodically anyway
+    pub fn start_autosave() {
+        thread::spawn(|| loop {
+            {
+                let (dirty, cvar) = &*DIRTY;
+                let (mut dirty, _) = cvar
+                    .wait_timeout_while(
+                        dirty.lock().unwrap(),
+                        Duration::from_secs(settings().autosave_interval),
+                        |d| !*d,
+                    )
+                    .unwrap();
+                *dirty = false;
+            }
+            // let a burst of changes settle into one write
+            thread::sleep(Duration::from_millis(settings().autosave_delay_ms));
+            Self::sync_db();
+        });
+    }
+
     pub fn add_client(addr: SocketAddr) -> Uuid {
-        let mut cli_meta = CliData::default();
-        cli_meta.addr = addr.clone();
-        cli_meta.online = true;
+        let cli_meta = CliData {
+            addr,
+            online: true,
+            ..Default::default()
+        };
         let cli_uid = cli_meta.uid;
-        Self::_lock_write().push(cli_meta);
+        Self::_lock_write().insert(cli_meta);
         cli_uid
     }
 
     pub fn get_all_client_jobs(uid: Uuid) -> Option<Vec<CliTask>> {
-        if Self::_lock_read()
-            .iter()
-            .find(|cli| cli.uid == uid)
-            .unwrap_or(&CliData::default())
+        let jobs = Self::_lock_read()
+            .get(uid)?
             .jobs
-            .len()
-            > 0
-        {
-            return Some(
-                Self::_lock_write()
-                    .iter_mut()
-                    .find(|cli| cli.uid == uid)
-                    .unwrap()
-                    .jobs
-                    .drain(..)
-                    .collect(),
-            );
-        } else {
+            .drain(&QueueLimits::current());
+        if jobs.is_empty() {
             None
+        } else {
+            Self::mark_dirty();
+            Some(jobs)
         }
     }
 
@@ -149,111 +610,553 @@ impl ClientDB {
             .collect()
     }
 
+    /// None for anonymous connections and for uids that are gone, such as
+    /// a user deleted while the HTTP API was serving them
     pub fn get_username(uid: Uuid) -> Option<String> {
-        Self::_lock_read()
-            .iter()
-            .find(|c| c.uid == uid)
-            .expect(&format!("can't find {}", uid))
-            .login
-            .clone()
+        Self::_lock_read().get(uid)?.login.clone()
     }
 
-    pub fn get_client_by_username(username: &String) -> Option<Uuid> {
+    pub fn get_client_by_username(username: &str) -> Option<Uuid> {
+        Self::_lock_read().by_login(username).map(|cli| cli.uid)
+    }
+
+    /// Logins `matches` picks, for lookups the index can't do
+    pub fn find_logins<F: Fn(&str) -> bool>(matches: F) -> Vec<String> {
         Self::_lock_read()
             .iter()
-            .find(|cli| cli.login.is_some() && cli.login.as_ref().unwrap() == username)
-            .map(|cli| cli.uid)
+            .filter_map(|cli| cli.login.as_deref())
+            .filter(|login| matches(login))
+            .map(str::to_string)
+            .collect()
     }
 
     pub fn add_task(uid: Uuid, task: CliTask) -> RResult<()> {
-        Self::_lock_write()
-            .iter_mut()
-            .find(|cli| cli.uid == uid)
-            .unwrap()
-            .jobs
-            .push(task);
+        Self::_lock_read().push_job(uid, task, &QueueLimits::current())?;
+        Self::mark_dirty();
+        server::notify(uid);
         Ok(())
     }
 
-    pub fn add_broadcast_task(uid: Uuid, task: CliTask) -> RResult<()> {
-        let clients = Self::_lock_read()
-            .iter()
-            .map(|cli| cli.uid)
-            .collect::<Vec<Uuid>>();
-        for cli_uid in clients.into_iter() {
-            ClientDB::add_task(cli_uid, task.clone())?;
+    /// Returns how many full queues refused the task
+    pub fn add_broadcast_task(task: CliTask) -> RResult<usize> {
+        let (receivers, refused) = Self::_lock_read().broadcast(&task, &QueueLimits::current());
+        Self::mark_dirty();
+        server::notify_many(receivers);
+        Ok(refused)
+    }
+
+    pub fn join_room(uid: Uuid, room: &str) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        match db.get(uid) {
+            Some(cli) if cli.rooms.contains(room) => return Err(SError::AlreadyInRoom),
+            Some(cli) if cli.rooms.len() >= MAX_ROOMS => {
+                return Err(SError::TooManyRooms(MAX_ROOMS))
+            }
+            Some(_) => (),
+            None => return Err(SError::NoSuchUser),
         }
-        Self::update_cmd_ts(uid);
+        db.join(uid, room);
+        drop(db);
+        Self::mark_dirty();
         Ok(())
     }
 
+    pub fn part_room(uid: Uuid, room: &str) -> RResult<()> {
+        if !Self::_lock_write().part(uid, room) {
+            return Err(SError::NotInRoom);
+        }
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Every room with its number of members
+    pub fn get_rooms() -> Vec<(String, usize)> {
+        Self::_lock_read()
+            .rooms
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect()
+    }
+
+    /// Logins of the members of `room`, marked like in `get_all_users`
+    pub fn get_room_members(room: &str) -> RResult<Vec<String>> {
+        let db = Self::_lock_read();
+        let members = db.members(room).ok_or(SError::NoSuchRoom)?;
+        Ok(members
+            .map(|cli| {
+                let user = cli.login.clone().unwrap_or(cli.addr.to_string());
+                if cli.online {
+                    format!("{} {}", user, ONLINE)
+                } else {
+                    user
+                }
+            })
+            .collect())
+    }
+
+    /// Rooms `uid` is a member of
+    pub fn get_user_rooms(uid: Uuid) -> Vec<String> {
+        Self::_lock_read()
+            .get(uid)
+            .map(|cli| cli.rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Accounts with something queued and how many jobs they have
+    pub fn get_queue_sizes() -> Vec<(String, usize)> {
+        let db = Self::_lock_read();
+        db.expire_jobs(&QueueLimits::current());
+        db.iter()
+            .filter_map(|cli| Some((cli.login.clone()?, cli.jobs.len())))
+            .filter(|(_, len)| *len > 0)
+            .collect()
+    }
+
+    pub fn get_online_logins() -> Vec<String> {
+        let mut logins = Self::_lock_read()
+            .iter()
+            .filter(|cli| cli.online)
+            .filter_map(|cli| cli.login.clone())
+            .collect::<Vec<String>>();
+        logins.sort();
+        logins
+    }
+
+    /// Queue `task` for the members of `room`, `uid` has to be one of them
+    /// Returns how many full queues refused the task
+    pub fn add_room_task(uid: Uuid, room: &str, task: CliTask) -> RResult<usize> {
+        let (receivers, refused) = {
+            let db = Self::_lock_read();
+            match db.get(uid) {
+                Some(cli) if cli.rooms.contains(room) => {
+                    db.room_cast(room, &task, &QueueLimits::current())
+                }
+                Some(_) => return Err(SError::NotInRoom),
+                None => return Err(SError::NoSuchUser),
+            }
+        };
+        Self::mark_dirty();
+        server::notify_many(receivers);
+        Ok(refused)
+    }
+
     pub fn remove_cli(uid: Uuid) {
-        Self::_lock_write().retain(|cli| cli.uid != uid);
+        Self::_lock_write().remove(uid);
+        Self::mark_dirty();
     }
 
     pub fn set_online_status(uid: Uuid, online: bool) {
-        Self::_lock_write()
-            .iter_mut()
-            .find(|cli| cli.uid == uid)
-            .unwrap()
-            .online = online;
+        if let Some(cli) = Self::_lock_write().get_mut(uid) {
+            cli.online = online;
+        }
+        Self::mark_dirty();
     }
 
     pub fn set_login(uid: Uuid, addr: &SocketAddr, login: String, password: String) -> RResult<()> {
+        // hashing is slow, so it's done with no locks held and the checks
+        // are repeated under the write lock afterwards. Connections run
+        // LOGIN off the reactor thread, see `Session::is_slow`.
+        let taken_by_other =
+            |db: &Store| matches!(db.by_login(&login), Some(cli) if cli.uid != uid);
         if Self::is_logged_in(uid) {
-            if Self::_lock_read()
-                .iter()
-                .any(|cli| cli.login.as_ref() == Some(&login) && cli.uid != uid)
-            {
+            if taken_by_other(&Self::_lock_read()) {
+                return Err(SError::LoginAlreadyExists);
+            }
+            let password = hash_password(&password);
+            let mut db = Self::_lock_write();
+            if taken_by_other(&db) {
                 return Err(SError::LoginAlreadyExists);
             }
-            if let Some(client) = Self::_lock_write().iter_mut().find(|cli| cli.uid == uid) {
-                client.login = Some(login);
+            db.set_login(uid, login);
+            if let Some(client) = db.get_mut(uid) {
                 client.password = Some(password);
+                client.password_hashed = Some(true);
                 client.online = true;
             }
-            Ok(())
-        } else {
-            let mut perform_login = false;
-            if let Some(cli) = Self::_lock_read()
-                .iter()
-                .find(|cli| cli.login.as_ref() == Some(&login))
-            {
-                if cli.password.as_ref() != Some(&password) {
-                    return Err(SError::WrongPassword);
-                }
-                if cli.online {
-                    return Err(SError::AlreadyLoggedIn);
-                }
-                perform_login = true;
+            drop(db);
+            Self::mark_dirty();
+            return Ok(());
+        }
+        let stored = Self::_lock_read().by_login(&login).map(|cli| {
+            (
+                cli.password.clone().unwrap_or_default(),
+                cli.password_hashed == Some(true),
+                cli.ban.clone(),
+            )
+        });
+        if let Some((stored, hashed, ban)) = stored {
+            let rehashed = match verify_password(&stored, hashed, &password) {
+                Verdict::Wrong => return Err(SError::WrongPassword),
+                Verdict::Ok => None,
+                Verdict::OkPlaintext => Some(hash_password(&password)),
+            };
+            if let Some(ban) = ban {
+                return Err(SError::Banned(ban.reason));
             }
-            if perform_login {
-                Self::remove_cli(uid);
-                if let Some(cli) = Self::_lock_write()
-                    .iter_mut()
-                    .find(|cli| cli.login.as_ref() == Some(&login))
-                {
-                    cli.addr = *addr;
-                    cli.online = true;
-                    cli.uid = uid;
+            // only checked now, a connection that left while its LOGIN
+            // was hashing has just been set offline
+            let mut db = Self::_lock_write();
+            let old_uid = match db.by_login(&login) {
+                Some(cli) if !cli.online => cli.uid,
+                Some(_) => return Err(SError::AlreadyLoggedIn),
+                None => return Err(SError::NoSuchUser),
+            };
+            // the account takes over the uid of this connection
+            db.remove(uid);
+            if let Some(mut cli) = db.remove(old_uid) {
+                cli.addr = *addr;
+                cli.online = true;
+                cli.uid = uid;
+                if rehashed.is_some() {
+                    info!("Migrated plaintext password of {} to a hash", login);
+                    cli.password = rehashed;
+                    cli.password_hashed = Some(true);
                 }
-                return Ok(());
+                db.insert(cli);
             }
-            if let Some(client) = Self::_lock_write().iter_mut().find(|cli| cli.uid == uid) {
-                client.login = Some(login);
-                client.password = Some(password);
-                client.online = true;
+            drop(db);
+            Self::mark_dirty();
+            // deliver whatever was queued while the user was offline
+            server::notify(uid);
+            return Ok(());
+        }
+        let password = hash_password(&password);
+        let mut db = Self::_lock_write();
+        if taken_by_other(&db) {
+            return Err(SError::LoginAlreadyExists);
+        }
+        db.set_login(uid, login);
+        if let Some(client) = db.get_mut(uid) {
+            client.password = Some(password);
+            client.password_hashed = Some(true);
+            client.online = true;
+        }
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Role of a logged in client. The admin from the settings is always
+    /// an admin, whatever is stored.
+    pub fn get_role(uid: Uuid) -> Option<Role> {
+        let db = Self::_lock_read();
+        let cli = db.get(uid)?;
+        let login = cli.login.as_ref()?;
+        if *login == settings().admin {
+            Some(Role::Admin)
+        } else {
+            Some(cli.role)
+        }
+    }
+
+    pub fn set_role(login: &str, role: Role) -> RResult<()> {
+        if login == settings().admin {
+            return Err(SError::InvalidArg(
+                "role of the configured admin can't be changed".to_string(),
+            ));
+        }
+        let mut db = Self::_lock_write();
+        let uid = db.by_login(login).ok_or(SError::NoSuchUser)?.uid;
+        if let Some(cli) = db.get_mut(uid) {
+            cli.role = role;
+        }
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Fails if a moderator `actor` may not act on the account `target`
+    fn check_moderatable(db: &Store, actor: Uuid, target: &str) -> RResult<Uuid> {
+        let target = db.by_login(target).ok_or(SError::NoSuchUser)?;
+        let role_of = |cli: &CliData| {
+            if cli.login.as_deref() == Some(settings().admin.as_str()) {
+                Role::Admin
+            } else {
+                cli.role
+            }
+        };
+        let actor_role = db.get(actor).map(role_of).unwrap_or_default();
+        if target.uid == actor || role_of(target) >= actor_role {
+            return Err(SError::Untouchable);
+        }
+        Ok(target.uid)
+    }
+
+    pub fn kick(actor: Uuid, login: &str) -> RResult<()> {
+        let db = Self::_lock_read();
+        let uid = Self::check_moderatable(&db, actor, login)?;
+        if !db.get(uid).map(|cli| cli.online).unwrap_or(false) {
+            return Err(SError::NotOnline);
+        }
+        server::disconnect(uid);
+        Ok(())
+    }
+
+    /// Mute for `minutes`, 0 lifts the mute
+    pub fn mute(actor: Uuid, login: &str, minutes: u64) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        let uid = Self::check_moderatable(&db, actor, login)?;
+        if let Some(cli) = db.get_mut(uid) {
+            cli.muted_until = match minutes {
+                0 => None,
+                m => Some(SystemTime::now() + Duration::from_secs(m * 60)),
+            };
+        }
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Fails with the number of minutes left if `uid` is muted
+    pub fn check_muted(uid: Uuid) -> RResult<()> {
+        let until = Self::_lock_read().get(uid).and_then(|cli| cli.muted_until);
+        match until.and_then(|u| u.duration_since(SystemTime::now()).ok()) {
+            Some(left) => Err(SError::Muted(left.as_secs() / 60 + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Ban the account and drop its connection, if any
+    pub fn ban(actor: Uuid, login: &str, ban: Ban) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        let uid = Self::check_moderatable(&db, actor, login)?;
+        if let Some(cli) = db.get_mut(uid) {
+            cli.ban = Some(ban);
+            if cli.online {
+                server::disconnect(uid);
+            }
+        }
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    pub fn unban(login: &str) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        let uid = db.by_login(login).ok_or(SError::NoSuchUser)?.uid;
+        match db.get_mut(uid) {
+            Some(cli) if cli.ban.is_some() => cli.ban = None,
+            _ => return Err(SError::NotBanned),
+        }
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Ban the address and drop every connection from it
+    pub fn ban_ip(addr: IpAddr, ban: Ban) {
+        let mut db = Self::_lock_write();
+        db.ip_bans.insert(addr, ban.clone());
+        db.unsaved_ip_bans.insert(addr, Some(ban));
+        for uid in db.connected_from(addr) {
+            server::disconnect(uid);
+        }
+        drop(db);
+        Self::mark_dirty();
+    }
+
+    pub fn unban_ip(addr: IpAddr) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        if db.ip_bans.remove(&addr).is_none() {
+            return Err(SError::NotBanned);
+        }
+        db.unsaved_ip_bans.insert(addr, None);
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Read the banned addresses from the storage again, e.g. after the
+    /// bans file was edited by hand, and drop connections from them
+    pub fn reload_ip_bans() -> Result<usize, String> {
+        let ip_bans = match STORAGE.read().unwrap().as_ref() {
+            Some(storage) => storage
+                .load_ip_bans()
+                .map_err(|e| format!("{} ({})", e, storage.describe()))?,
+            None => return Ok(0),
+        };
+        let mut db = Self::_lock_write();
+        db.ip_bans = ip_bans.into_iter().map(|b| (b.addr, b.ban)).collect();
+        // the file may not have the latest bans and unbans yet
+        for (addr, ban) in db.unsaved_ip_bans.clone() {
+            match ban {
+                Some(ban) => db.ip_bans.insert(addr, ban),
+                None => db.ip_bans.remove(&addr),
+            };
+        }
+        for addr in db.ip_bans.keys() {
+            for uid in db.connected_from(*addr) {
+                server::disconnect(uid);
             }
-            Ok(())
+        }
+        Ok(db.ip_bans.len())
+    }
+
+    pub fn get_ip_ban(addr: IpAddr) -> Option<Ban> {
+        Self::_lock_read().ip_bans.get(&addr).cloned()
+    }
+
+    /// New API token for the account of `uid`, the previous one stops working
+    pub fn issue_token(uid: Uuid) -> RResult<String> {
+        if !Self::is_logged_in(uid) {
+            return Err(SError::NotLoggedIn);
+        }
+        let token = new_token();
+        if !Self::_lock_write().set_token(uid, Some(hash_token(&token))) {
+            return Err(SError::NotLoggedIn);
+        }
+        Self::mark_dirty();
+        Ok(token)
+    }
+
+    pub fn revoke_token(uid: Uuid) -> RResult<()> {
+        let mut db = Self::_lock_write();
+        match db.get(uid) {
+            Some(cli) if cli.api_token.is_some() => db.set_token(uid, None),
+            _ => return Err(SError::InvalidToken),
+        };
+        drop(db);
+        Self::mark_dirty();
+        Ok(())
+    }
+
+    /// Account the API `token` belongs to
+    pub fn token_user(token: &str) -> RResult<Uuid> {
+        let db = Self::_lock_read();
+        let cli = db
+            .api_tokens
+            .get(&hash_token(token))
+            .and_then(|uid| db.get(*uid))
+            .ok_or(SError::InvalidToken)?;
+        match cli.ban.as_ref() {
+            Some(ban) => Err(SError::Banned(ban.reason.clone())),
+            None => Ok(cli.uid),
         }
     }
 
     pub fn is_logged_in(uid: Uuid) -> bool {
         Self::_lock_read()
-            .iter()
-            .find(|c| c.uid == uid)
-            .unwrap_or(&CliData::default())
-            .login
-            .is_some()
+            .get(uid)
+            .map(|c| c.login.is_some())
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_LIMITS: QueueLimits = QueueLimits {
+        max: usize::MAX,
+        ttl: None,
+        reject: false,
+    };
+
+    #[test]
+    fn test_store_index() {
+        let mut store = Store::new(vec![CliData::named("foo"), CliData::default()]);
+        let uid = store.by_login("foo").unwrap().uid;
+        store.set_login(uid, "bar".to_string());
+        assert!(store.by_login("foo").is_none());
+        assert_eq!(store.by_login("bar").unwrap().uid, uid);
+        assert!(store.push_job(uid, CliTask::Exit, &NO_LIMITS).is_ok());
+        // anonymous connections aren't broadcast to
+        assert_eq!(store.broadcast(&CliTask::Exit, &NO_LIMITS).0.len(), 1);
+        assert_eq!(store.get(uid).unwrap().jobs().len(), 2);
+        assert!(store.set_token(uid, Some("t1".to_string())));
+        assert!(store.set_token(uid, Some("t2".to_string())));
+        assert_eq!(store.api_tokens.get("t2"), Some(&uid));
+        assert!(!store.api_tokens.contains_key("t1"));
+        store.remove(uid);
+        assert!(store.by_login("bar").is_none());
+        assert!(store.api_tokens.is_empty());
+        assert!(matches!(
+            store.push_job(uid, CliTask::Exit, &NO_LIMITS),
+            Err(SError::NoSuchUser)
+        ));
+    }
+
+    #[test]
+    fn test_ip_ban_reload() {
+        ClientDB::init_test_db();
+        let saved: IpAddr = "10.9.8.6".parse().unwrap();
+        let unsaved: IpAddr = "10.9.8.7".parse().unwrap();
+        ClientDB::ban_ip(saved, Ban::new("mod".into(), "spam".into()));
+        ClientDB::sync_db();
+        ClientDB::unban_ip(saved).unwrap();
+        ClientDB::ban_ip(unsaved, Ban::new("mod".into(), "bot".into()));
+        ClientDB::reload_ip_bans().unwrap();
+        assert!(ClientDB::get_ip_ban(saved).is_none());
+        assert_eq!(ClientDB::get_ip_ban(unsaved).unwrap().reason, "bot");
+        ClientDB::unban_ip(unsaved).unwrap();
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut store = Store::new(vec![CliData::named("foo"), CliData::named("bar")]);
+        let foo = store.by_login("foo").unwrap().uid;
+        let bar = store.by_login("bar").unwrap().uid;
+        assert!(store.join(foo, "lab1"));
+        assert!(!store.join(foo, "lab1"));
+        assert!(store.join(bar, "lab1"));
+        assert!(store.join(bar, "lab2"));
+        let (receivers, refused) = store.room_cast("lab1", &CliTask::Exit, &NO_LIMITS);
+        assert_eq!((receivers.len(), refused), (2, 0));
+        // both queues already hold the EXIT
+        let full = QueueLimits {
+            max: 1,
+            ttl: None,
+            reject: true,
+        };
+        let msg = CliTask::SendMsg("now".into(), "foo".into(), "hi".into());
+        assert_eq!(store.room_cast("lab1", &msg, &full), (vec![], 2));
+        assert!(store
+            .room_cast("nowhere", &CliTask::Exit, &NO_LIMITS)
+            .0
+            .is_empty());
+
+        // membership survives a reload and the index is rebuilt from it
+        let mut store = Store::new(store.iter().cloned().collect());
+        assert!(store.part(bar, "lab2"));
+        assert!(!store.part(bar, "lab2"));
+        assert!(!store.rooms.contains_key("lab2"));
+        store.remove(foo);
+        assert_eq!(store.members("lab1").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_queue_limits() {
+        let msg = |text: &str| CliTask::SendMsg("now".into(), "foo".into(), text.into());
+        let text = |task: &CliTask| match task {
+            CliTask::SendMsg(_, _, text) => text.clone(),
+            task => panic!("unexpected job: {:?}", task),
+        };
+        let mut limits = QueueLimits {
+            max: 2,
+            ttl: None,
+            reject: false,
+        };
+        let queue = JobQueue::default();
+        for t in &["a", "b", "c"] {
+            assert!(queue.push(msg(t), &limits));
+        }
+        let kept = queue.drain(&limits);
+        assert_eq!(kept.iter().map(text).collect::<Vec<_>>(), ["b", "c"]);
+
+        limits.reject = true;
+        assert!(queue.push(msg("a"), &limits));
+        assert!(queue.push(msg("b"), &limits));
+        assert!(!queue.push(msg("c"), &limits));
+        assert!(queue.push(CliTask::Exit, &limits));
+
+        // a job queued a day ago is past a one hour TTL
+        queue.0.lock().unwrap()[0].at -= Duration::from_secs(24 * 60 * 60);
+        limits.ttl = Some(Duration::from_secs(60 * 60));
+        assert_eq!(queue.expire(&limits), 1);
+        assert_eq!(queue.len(), 2);
+
+        // records from before jobs had a date still load
+        let old: JobQueue = serde_json::from_str(r#"["Exit",{"SendMsg":["d","f","m"]}]"#).unwrap();
+        assert_eq!(old.drain(&limits).len(), 2);
     }
 }
@@ -1,12 +1,17 @@
 use thiserror::Error;
 
+use crate::auth::Role;
+
 #[derive(Error, Debug)]
 pub enum SError {
     #[error("Already logged in")]
     AlreadyLoggedIn,
 
-    #[error("Too fast")]
-    DOS,
+    #[error("Too fast, retry after {} ms", .0)]
+    DOS(u64),
+
+    #[error("Too many connections from your address, {} at max", .0)]
+    TooManyConnections(usize),
 
     #[error("Login already exists")]
     LoginAlreadyExists,
@@ -19,6 +24,27 @@ pub enum SError {
     #[error("Please log in")]
     NotLoggedIn,
 
+    #[error("Permission denied: {} role required", .0)]
+    PermissionDenied(Role),
+
+    #[error("Can't moderate yourself or users with the same or higher role")]
+    Untouchable,
+
+    #[error("User is offline")]
+    NotOnline,
+
+    #[error("User's message queue is full: {} messages at max", .0)]
+    QueueFull(usize),
+
+    #[error("Muted for {} more minutes", .0)]
+    Muted(u64),
+
+    #[error("Banned: {}", .0)]
+    Banned(String),
+
+    #[error("Not banned")]
+    NotBanned,
+
     #[error("No such user")]
     NoSuchUser,
 
@@ -31,6 +57,130 @@ pub enum SError {
     #[error("Wrong password")]
     WrongPassword,
 
+    #[error("Missing or invalid API token")]
+    InvalidToken,
+
     #[error("Syntax error: {}", .0)]
     SyntaxError(String),
+
+    #[error("Line too long: {} bytes at max", .0)]
+    LineTooLong(usize),
+
+    #[error("Unknown protocol, available: text, json")]
+    UnknownProto,
+
+    #[error("Unknown language, available: en, ru")]
+    UnknownLang,
+
+    #[error("Invalid room name: latin letters, digits, '_' and '-', 32 chars at max")]
+    InvalidRoom,
+
+    #[error("No such room")]
+    NoSuchRoom,
+
+    #[error("Not in this room")]
+    NotInRoom,
+
+    #[error("Already in this room")]
+    AlreadyInRoom,
+
+    #[error("Too many rooms: {} at max", .0)]
+    TooManyRooms(usize),
+
+    #[error("Invalid argument: {}", .0)]
+    InvalidArg(String),
+
+    #[error("Config not reloaded: {}", .0)]
+    Reload(String),
+
+    #[error("Internal server error")]
+    Internal,
+}
+
+impl SError {
+    /// Stable code sent along with the message, which may be translated.
+    /// Codes never change meaning, new errors get new codes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SError::SyntaxError(_) => "E400",
+            SError::NotLoggedIn => "E401",
+            SError::PermissionDenied(_) => "E403",
+            SError::UnknownCommand => "E404",
+            SError::WrongArgs(_) => "E405",
+            SError::InvalidArg(_) => "E406",
+            SError::InvalidToken => "E407",
+            SError::AlreadyLoggedIn => "E409",
+            SError::WrongPassword => "E410",
+            SError::InvalidLogin => "E411",
+            SError::LoginAlreadyExists => "E412",
+            SError::LineTooLong(_) => "E413",
+            SError::UnknownProto => "E415",
+            SError::UnknownLang => "E416",
+            SError::Untouchable => "E420",
+            SError::Muted(_) => "E421",
+            SError::Banned(_) => "E422",
+            SError::NotBanned => "E423",
+            SError::TooManyConnections(_) => "E428",
+            SError::DOS(_) => "E429",
+            SError::NoSuchUser => "E430",
+            SError::NotOnline => "E431",
+            SError::QueueFull(_) => "E432",
+            SError::InvalidRoom => "E440",
+            SError::NoSuchRoom => "E441",
+            SError::NotInRoom => "E442",
+            SError::AlreadyInRoom => "E443",
+            SError::TooManyRooms(_) => "E444",
+            SError::Internal => "E500",
+            SError::Reload(_) => "E501",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Can't read {}: {}", .0, .1)]
+    Io(String, std::io::Error),
+
+    #[error("Can't parse {}: {}", .0, .1)]
+    Parse(String, toml::de::Error),
+
+    #[error("Invalid config: {}", .0)]
+    Invalid(String),
+
+    #[error("Can't set up TLS: {}", .0)]
+    Tls(String),
+}
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("I/O error: {}", .0)]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialization error: {}", .0)]
+    Json(#[from] serde_json::Error),
+
+    #[error("SQLite error: {}", .0)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Corrupted db: {}", .0)]
+    Corrupted(String),
+
+    #[error("Unknown storage backend '{}', available: json, sqlite", .0)]
+    UnknownBackend(String),
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("I/O error: {}", .0)]
+    Io(#[from] std::io::Error),
+
+    /// Code and message of a `-` reply
+    #[error("Server refused: {} {}", .0, .1)]
+    Refused(String, String),
+
+    #[error("Unexpected line from server: {}", .0)]
+    Protocol(String),
+
+    #[error("Connection closed by server")]
+    Closed,
 }
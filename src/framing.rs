@@ -0,0 +1,116 @@
+use crate::error::SError;
+
+const LF: u8 = b'\n';
+const CR: u8 = b'\r';
+
+/// Splits a byte stream into `\n`-terminated lines (`\r\n` is accepted too).
+///
+/// Bytes are kept until a full line arrives, so multibyte characters split
+/// between reads are glued back together. A line longer than `max_len` is
+/// reported once and then skipped up to the next newline.
+pub struct LineReader {
+    buf: Vec<u8>,
+    max_len: usize,
+    discarding: bool,
+}
+
+impl LineReader {
+    pub fn new(max_len: usize) -> LineReader {
+        LineReader {
+            buf: vec![],
+            max_len,
+            discarding: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Next complete line, if any. Empty lines are skipped.
+    pub fn next_line(&mut self) -> Option<Result<String, SError>> {
+        loop {
+            let pos = match self.buf.iter().position(|b| *b == LF) {
+                Some(p) => p,
+                None => {
+                    // the terminator may still be on its way
+                    let pending = self.buf.len().saturating_sub(1);
+                    if !self.discarding && pending > self.max_len {
+                        self.buf.clear();
+                        self.discarding = true;
+                        return Some(Err(SError::LineTooLong(self.max_len)));
+                    }
+                    if self.discarding {
+                        self.buf.clear();
+                    }
+                    return None;
+                }
+            };
+            let mut line = self.buf.drain(..=pos).collect::<Vec<u8>>();
+            line.pop();
+            if line.last() == Some(&CR) {
+                line.pop();
+            }
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            if line.len() > self.max_len {
+                return Some(Err(SError::LineTooLong(self.max_len)));
+            }
+            if line.is_empty() {
+                continue;
+            }
+            return Some(
+                String::from_utf8(line)
+                    .map_err(|_| SError::SyntaxError("invalid UTF-8 sequence".to_string())),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(reader: &mut LineReader) -> Vec<Result<String, String>> {
+        std::iter::from_fn(|| reader.next_line())
+            .map(|l| l.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelined_commands() {
+        let mut reader = LineReader::new(256);
+        reader.feed(b"PING\nSEND|username=a|msg=b\r\nUSERS");
+        assert_eq!(
+            lines(&mut reader),
+            vec![Ok("PING".to_string()), Ok("SEND|username=a|msg=b".to_string())]
+        );
+        reader.feed(b"\n\n");
+        assert_eq!(lines(&mut reader), vec![Ok("USERS".to_string())]);
+    }
+
+    #[test]
+    fn test_split_utf8() {
+        let mut reader = LineReader::new(256);
+        let msg = "ECHO|msg=привет\n".as_bytes();
+        reader.feed(&msg[..12]);
+        assert!(lines(&mut reader).is_empty());
+        reader.feed(&msg[12..]);
+        assert_eq!(lines(&mut reader), vec![Ok("ECHO|msg=привет".to_string())]);
+    }
+
+    #[test]
+    fn test_too_long() {
+        let mut reader = LineReader::new(4);
+        reader.feed(b"PING\nECHO|");
+        assert_eq!(lines(&mut reader), vec![Ok("PING".to_string())]);
+        reader.feed(b"msg=spam spam");
+        assert!(lines(&mut reader)[0].is_err());
+        reader.feed(b" spam\nPING\r\n");
+        assert_eq!(lines(&mut reader), vec![Ok("PING".to_string())]);
+        reader.feed(b"ECHOS\n");
+        assert!(lines(&mut reader)[0].is_err());
+    }
+}
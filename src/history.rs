@@ -0,0 +1,143 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A delivered message as kept in the history store
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Message {
+    /// Assigned by the storage, grows with every message
+    pub id: u64,
+    pub date: String,
+    pub from: String,
+    /// Receiver of a direct message, `None` for a message to all
+    pub to: Option<String>,
+    pub text: String,
+}
+
+impl Message {
+    pub fn new(from: String, to: Option<String>, text: String) -> Message {
+        Message {
+            id: 0,
+            date: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            from,
+            to,
+            text,
+        }
+    }
+
+    /// A direct message `login` has sent or received
+    pub fn is_dm_of(&self, login: &str) -> bool {
+        match self.to.as_deref() {
+            Some(to) => to == login || self.from == login,
+            None => false,
+        }
+    }
+
+    /// `id [date from -> to]: text`, a text of several lines goes on in
+    /// lines starting with a space, so they can't pass for other messages
+    pub fn to_line(&self) -> String {
+        let to = match self.to.as_ref() {
+            Some(to) => format!("-> {}", to),
+            None => "(to all)".to_string(),
+        };
+        format!(
+            "{} [{} {} {}]: {}",
+            self.id,
+            self.date,
+            self.from,
+            to,
+            self.text.replace('\n', "\n ")
+        )
+    }
+}
+
+/// Which part of the history `user` wants to see
+pub struct HistoryQuery {
+    pub user: String,
+    /// Only the direct conversation with this user
+    pub with: Option<String>,
+    /// Only messages with smaller ids, for scrolling back
+    pub before: Option<u64>,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    pub fn matches(&self, msg: &Message) -> bool {
+        if matches!(self.before, Some(before) if msg.id >= before) {
+            return false;
+        }
+        let me = Some(&self.user);
+        match self.with.as_ref() {
+            Some(with) => {
+                (msg.from == self.user && msg.to.as_ref() == Some(with))
+                    || (&msg.from == with && msg.to.as_ref() == me)
+            }
+            None => msg.to.is_none() || msg.from == self.user || msg.to.as_ref() == me,
+        }
+    }
+
+    /// Newest `limit` matching messages, oldest first
+    pub fn select<'m, I>(&self, messages: I) -> Vec<Message>
+    where
+        I: DoubleEndedIterator<Item = &'m Message>,
+    {
+        let mut found = messages
+            .rev()
+            .filter(|m| self.matches(m))
+            .take(self.limit)
+            .cloned()
+            .collect::<Vec<Message>>();
+        found.reverse();
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: u64, from: &str, to: Option<&str>) -> Message {
+        Message {
+            id,
+            from: from.to_string(),
+            to: to.map(str::to_string),
+            ..Message::new(String::new(), None, "hi|there".to_string())
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let all = [
+            msg(1, "a", None),
+            msg(2, "a", Some("b")),
+            msg(3, "b", Some("a")),
+            msg(4, "c", Some("b")),
+            msg(5, "a", Some("c")),
+        ];
+        let query = |with: Option<&str>, before, limit| HistoryQuery {
+            user: "a".to_string(),
+            with: with.map(str::to_string),
+            before,
+            limit,
+        };
+        let ids = |q: HistoryQuery| {
+            q.select(all.iter())
+                .iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(query(None, None, 10)), vec![1, 2, 3, 5]);
+        assert_eq!(ids(query(None, None, 2)), vec![3, 5]);
+        assert_eq!(ids(query(None, Some(3), 10)), vec![1, 2]);
+        assert_eq!(ids(query(Some("b"), None, 10)), vec![2, 3]);
+        assert_eq!(ids(query(Some("c"), None, 10)), vec![5]);
+        assert_eq!(
+            all[1].to_line(),
+            format!("2 [{} a -> b]: hi|there", all[1].date)
+        );
+        let forged = Message {
+            text: "hi\n6 [now x -> b]: fake".to_string(),
+            ..msg(4, "c", Some("b"))
+        };
+        assert!(forged.to_line().ends_with(": hi\n 6 [now x -> b]: fake"));
+    }
+}
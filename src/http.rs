@@ -0,0 +1,331 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    api::{process_command, staff_command, Command, RResult},
+    client::Session,
+    codec::{json_arg, Proto},
+    config::settings,
+    db::ClientDB,
+    error::SError,
+    lang::Lang,
+};
+
+/// Requests handled at once, a client slowly sending its body holds one
+const WORKERS: usize = 4;
+
+/// The threads serving the REST API, stopped along with the reactor
+pub struct HttpApi {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+    unblocked: bool,
+}
+
+impl HttpApi {
+    /// Take no more requests, those already received are still served
+    pub fn unblock(&mut self) {
+        if self.unblocked {
+            return;
+        }
+        // each call lets one worker out
+        for _ in &self.workers {
+            self.server.unblock();
+        }
+        self.unblocked = true;
+    }
+
+    /// Wait for the requests in progress until `deadline`, a client
+    /// sending its body slowly can't hold the shutdown forever
+    pub fn join(mut self, deadline: Instant) {
+        self.unblock();
+        while self.workers.iter().any(|w| !w.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let busy = self.workers.iter().filter(|w| !w.is_finished()).count();
+        if busy > 0 {
+            warn!("Leaving {} HTTP requests unfinished", busy);
+        }
+    }
+}
+
+/// Serve the REST API on `addr` from threads of its own. Every endpoint is
+/// a command run on behalf of the owner of the API token, so roles, mutes
+/// and rate limits apply just like on a socket.
+pub fn start(addr: SocketAddr) -> io::Result<HttpApi> {
+    let server = Arc::new(Server::http(addr).map_err(io::Error::other)?);
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request);
+                }
+            })
+        })
+        .collect();
+    Ok(HttpApi {
+        server,
+        workers,
+        unblocked: false,
+    })
+}
+
+/// The token of an `Authorization: Bearer <token>` header, the scheme is
+/// case-insensitive
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+fn status(reply: &RResult<String>) -> u16 {
+    match reply {
+        Ok(_) => 200,
+        Err(SError::InvalidToken) | Err(SError::NotLoggedIn) => 401,
+        Err(SError::PermissionDenied(_))
+        | Err(SError::Untouchable)
+        | Err(SError::Banned(_))
+        | Err(SError::Muted(_)) => 403,
+        Err(SError::UnknownCommand) | Err(SError::NoSuchUser) | Err(SError::NoSuchRoom) => 404,
+        Err(SError::LineTooLong(_)) => 413,
+        Err(SError::DOS(_)) | Err(SError::QueueFull(_)) => 429,
+        Err(SError::Internal) => 500,
+        Err(_) => 400,
+    }
+}
+
+fn handle(mut request: Request) {
+    let addr = request
+        .remote_addr()
+        .copied()
+        .unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+    let token = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| bearer_token(h.value.as_str()))
+        .map(str::to_string);
+    // only the first choice counts, "ru-RU,ru;q=0.9,en;q=0.8" is Russian
+    let lang = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept-Language"))
+        .and_then(|h| h.value.as_str().get(..2)?.parse::<Lang>().ok())
+        .unwrap_or_default();
+    let limit = settings().read_buf_size;
+    let mut body = String::new();
+    let read = request
+        .as_reader()
+        .take(limit as u64 + 1)
+        .read_to_string(&mut body);
+    let reply = match read {
+        Err(e) => Err(SError::SyntaxError(e.to_string())),
+        Ok(n) if n > limit => Err(SError::LineTooLong(limit)),
+        Ok(_) => {
+            let method = request.method().clone();
+            let url = request.url().to_string();
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                route(&method, &url, token.as_deref(), &body, &addr)
+            }))
+            .unwrap_or(Err(SError::Internal))
+        }
+    };
+    match &reply {
+        Ok(_) => info!("HTTP {} {} from {}", request.method(), request.url(), addr),
+        Err(e) => error!(
+            "HTTP {} {} from {} ({})",
+            request.method(),
+            request.url(),
+            addr,
+            e
+        ),
+    }
+    let json = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(Proto::Json.encode_reply(lang, None, &reply))
+        .with_status_code(status(&reply))
+        .with_header(json);
+    if let Err(e) = request.respond(response) {
+        error!("Can't respond to {}: {}", addr, e);
+    }
+}
+
+/// Decode `%XX` and `+` of a query string part
+fn url_decode(s: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' if rest.len() >= 2 => {
+                let hex = std::str::from_utf8(&rest[..2]).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'%' => return None,
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn query_args(query: &str) -> RResult<HashMap<String, String>> {
+    let bad = || SError::SyntaxError("bad query string".to_string());
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((
+                url_decode(k).ok_or_else(bad)?,
+                url_decode(v).ok_or_else(bad)?,
+            ))
+        })
+        .collect()
+}
+
+/// Command args from a JSON object, like in the json protocol
+fn body_args(body: &str) -> RResult<HashMap<String, String>> {
+    if body.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let args = serde_json::from_str::<HashMap<String, Value>>(body)
+        .map_err(|e| SError::SyntaxError(e.to_string()))?;
+    Ok(args.into_iter().map(|(k, v)| (k, json_arg(v))).collect())
+}
+
+fn route(
+    method: &Method,
+    url: &str,
+    token: Option<&str>,
+    body: &str,
+    addr: &SocketAddr,
+) -> RResult<String> {
+    if let Some(ban) = ClientDB::get_ip_ban(addr.ip()) {
+        return Err(SError::Banned(ban.reason));
+    }
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    if let (Method::Get, "/health") = (method, path) {
+        return Ok(String::new());
+    }
+    let uid = ClientDB::token_user(token.ok_or(SError::InvalidToken)?)?;
+    let args = match method {
+        Method::Get => query_args(query)?,
+        Method::Post => body_args(body)?,
+        _ => return Err(SError::UnknownCommand),
+    };
+    let cmd = match (method, path) {
+        (Method::Get, "/users") => "USERS",
+        (Method::Get, "/history") => "HISTORY",
+        // whom to is told by the args, as in SEND, SENDROOM and SNDALL
+        (Method::Post, "/messages") if args.contains_key("username") => "SEND",
+        (Method::Post, "/messages") if args.contains_key("room") => "SENDROOM",
+        (Method::Post, "/messages") => "SNDALL",
+        (Method::Post, _) => path
+            .strip_prefix("/admin/")
+            .and_then(staff_command)
+            .ok_or(SError::UnknownCommand)?,
+        _ => return Err(SError::UnknownCommand),
+    };
+    let command = Command {
+        cmd: cmd.into(),
+        args: args
+            .iter()
+            .map(|(k, v)| (k.as_str().into(), v.clone()))
+            .collect(),
+    };
+    process_command(command, uid, addr, &mut Session::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        ClientDB::init_test_db();
+        let addr = "127.0.0.1:4321".parse().unwrap();
+        let login = |name: &str| {
+            let uid = ClientDB::add_client(addr);
+            ClientDB::set_login(uid, &addr, name.to_string(), "pw".to_string()).unwrap();
+            uid
+        };
+        let bot = login("http_bot");
+        let student = login("http_student");
+        let token = ClientDB::issue_token(bot).unwrap();
+        let call = |method, url, token, body| route(&method, url, token, body, &addr);
+
+        assert!(call(Method::Get, "/health", None, "").is_ok());
+        assert!(matches!(
+            call(Method::Get, "/users", None, ""),
+            Err(SError::InvalidToken)
+        ));
+        assert!(matches!(
+            call(Method::Get, "/users", Some("nope"), ""),
+            Err(SError::InvalidToken)
+        ));
+        assert!(call(Method::Get, "/users", Some(&token), "")
+            .unwrap()
+            .contains("http_student"));
+
+        let body = r#"{"username": "http_student", "msg": "build #42 passed"}"#;
+        call(Method::Post, "/messages", Some(&token), body).unwrap();
+        assert_eq!(ClientDB::get_all_client_jobs(student).unwrap().len(), 1);
+        let history = call(Method::Get, "/history?with=http_student", Some(&token), "");
+        assert!(history.unwrap().contains("build #42 passed"));
+        assert!(matches!(
+            call(Method::Get, "/history?limit=%zz", Some(&token), ""),
+            Err(SError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            call(Method::Post, "/messages", Some(&token), "{"),
+            Err(SError::SyntaxError(_))
+        ));
+
+        let kick = r#"{"username": "http_student"}"#;
+        assert!(matches!(
+            call(Method::Post, "/admin/kick", Some(&token), kick),
+            Err(SError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            call(Method::Post, "/admin/login", Some(&token), ""),
+            Err(SError::UnknownCommand)
+        ));
+        assert!(matches!(
+            call(Method::Get, "/nowhere", Some(&token), ""),
+            Err(SError::UnknownCommand)
+        ));
+
+        // a new token replaces the old one
+        let new_token = ClientDB::issue_token(bot).unwrap();
+        assert!(call(Method::Get, "/users", Some(&token), "").is_err());
+        assert!(call(Method::Get, "/users", Some(&new_token), "").is_ok());
+        ClientDB::revoke_token(bot).unwrap();
+        assert!(call(Method::Get, "/users", Some(&new_token), "").is_err());
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("a+b%20c%D0%B9").as_deref(), Some("a b cй"));
+        assert!(url_decode("%4").is_none());
+        assert!(url_decode("%ff").is_none());
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic YTpi"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+    }
+}
@@ -0,0 +1,581 @@
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    api::{process_command, Command, RResult},
+    client::Session,
+    codec::{Push, Scope},
+    config::*,
+    db::ClientDB,
+    error::SError,
+};
+
+pub const SERVER_NAME: &str = "pi_server";
+/// Longest IRC line with its CRLF, clients may send longer lines than our
+/// own protocol allows and don't take longer ones from us
+pub const MAX_LINE: usize = 512;
+/// Messages to everyone (SNDALL) appear in this channel
+const ALL_CHANNEL: &str = "#all";
+const NAMES_PER_LINE: usize = 20;
+/// These would break the `nick!user@host` prefix or a list of nicks
+const NOT_IN_NICK: &[char] = &[' ', ',', '!', '@', '*', '?'];
+/// These would make a nick look like a channel or a trailing param
+const NOT_FIRST_IN_NICK: &[char] = &['#', '&', '$', ':'];
+
+/// Registration of an IRC connection, which ends with a LOGIN
+#[derive(Default)]
+pub struct IrcState {
+    nick: Option<String>,
+    pass: Option<String>,
+    user: bool,
+    registered: bool,
+}
+
+impl IrcState {
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+}
+
+/// Command and params of a line, the prefix and IRCv3 tags are skipped
+fn parse(line: &str) -> Option<(String, Vec<&str>)> {
+    let mut rest = line.trim_end();
+    for marker in ['@', ':'].iter() {
+        if rest.starts_with(*marker) {
+            rest = rest.split_once(' ').map(|(_, r)| r).unwrap_or("");
+        }
+    }
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split(' ').filter(|w| !w.is_empty());
+    let cmd = words.next()?.to_uppercase();
+    let mut params = words.collect::<Vec<&str>>();
+    params.extend(trailing);
+    Some((cmd, params))
+}
+
+/// Login as IRC clients can parse it, users of the other protocols may
+/// have spaces and such in their names
+fn as_nick(login: &str) -> String {
+    login
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            c if NOT_IN_NICK.contains(&c) => '_',
+            c if i == 0 && NOT_FIRST_IN_NICK.contains(&c) => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn is_nick(nick: &str) -> bool {
+    !nick.is_empty() && as_nick(nick) == nick
+}
+
+/// Login that `as_nick` turned into `nick`, for replies to users of the
+/// other protocols. None when no login or several of them look like it.
+fn login_of(nick: &str) -> Option<String> {
+    if ClientDB::get_client_by_username(nick).is_some() {
+        return Some(nick.to_string());
+    }
+    let mut logins = ClientDB::find_logins(|login| as_nick(login) == nick);
+    match logins.len() {
+        1 => logins.pop(),
+        _ => None,
+    }
+}
+
+/// Text from users, such as a ban reason, put into a single line
+fn one_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+fn user_prefix(login: &str) -> String {
+    let nick = as_nick(login);
+    format!("{}!{}@{}", nick, nick, SERVER_NAME)
+}
+
+/// Pieces of `text` no longer than `max` bytes, unless a single char is
+fn split_at_most(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = max.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(0, char::len_utf8);
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces
+}
+
+/// A line per message line, IRC has no multiline messages and a lone CR
+/// ends a line too. Lines past `MAX_LINE` are split.
+fn privmsg(from: &str, target: &str, msg: &str) -> String {
+    let head = format!(":{} PRIVMSG {} :", user_prefix(from), target);
+    let room = MAX_LINE.saturating_sub(head.len() + 2);
+    msg.split(['\r', '\n'])
+        .flat_map(|line| split_at_most(line, room))
+        .map(|piece| format!("{}{}", head, piece))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+struct Conn<'a> {
+    uid: Uuid,
+    addr: &'a SocketAddr,
+    session: &'a mut Session,
+    state: IrcState,
+    out: Vec<String>,
+}
+
+impl Conn<'_> {
+    fn nick(&self) -> &str {
+        self.state.nick.as_deref().unwrap_or("*")
+    }
+
+    fn numeric(&mut self, code: &str, text: &str) {
+        let line = format!(":{} {} {} {}", SERVER_NAME, code, self.nick(), text);
+        self.out.push(line);
+    }
+
+    fn echo(&mut self, cmd: &str, channel: &str) {
+        let line = format!(":{} {} {}", user_prefix(self.nick()), cmd, channel);
+        self.out.push(line);
+    }
+
+    fn run(&mut self, cmd: &str, args: &[(&'static str, &str)]) -> RResult<String> {
+        let command = Command {
+            cmd: cmd.into(),
+            args: args
+                .iter()
+                .map(|(k, v)| ((*k).into(), v.to_string()))
+                .collect(),
+        };
+        process_command(command, self.uid, self.addr, self.session)
+    }
+
+    /// Tell why `target` failed in a numeric the clients know
+    fn fail(&mut self, target: &str, e: SError) {
+        let (code, text) = match e {
+            SError::NoSuchUser => ("401", "No such nick".to_string()),
+            SError::InvalidRoom | SError::NoSuchRoom => ("403", "No such channel".to_string()),
+            SError::NotInRoom => ("442", "You're not on that channel".to_string()),
+            SError::TooManyRooms(_) => ("405", e.to_string()),
+            e => ("404", one_line(&e.to_string())),
+        };
+        self.numeric(code, &format!("{} :{}", target, text));
+    }
+
+    /// Close the link, the reactor sends the ERROR line
+    fn quit(&mut self) {
+        self.run("EXIT", &[]).ok();
+    }
+
+    fn handle(&mut self, cmd: &str, params: &[&str]) {
+        let required = match cmd {
+            "PASS" | "NICK" | "USER" | "PRIVMSG" | "JOIN" | "PART" | "NAMES" | "MODE" => 1,
+            _ => 0,
+        };
+        if params.len() < required || (cmd == "PRIVMSG" && params.len() < 2) {
+            self.numeric("461", &format!("{} :Not enough parameters", cmd));
+            return;
+        }
+        match cmd {
+            "PING" => {
+                let token = params.first().copied().unwrap_or(SERVER_NAME);
+                self.out
+                    .push(format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token));
+            }
+            "PONG" => (),
+            "QUIT" => self.quit(),
+            "PASS" | "USER" if self.state.registered => {
+                self.numeric("462", ":You may not reregister")
+            }
+            "NICK" if self.state.registered => {
+                self.numeric("484", ":Nick changes are not supported")
+            }
+            "PASS" => self.state.pass = Some(params[0].to_string()),
+            "NICK" if !is_nick(params[0]) => {
+                self.numeric("432", &format!("{} :Erroneous nickname", params[0]))
+            }
+            "NICK" => {
+                self.state.nick = Some(params[0].to_string());
+                self.register();
+            }
+            "USER" => {
+                self.state.user = true;
+                self.register();
+            }
+            _ if !self.state.registered => self.numeric("451", ":You have not registered"),
+            "PRIVMSG" | "NOTICE" => {
+                let msg = params.get(1).copied().unwrap_or_default();
+                for target in params[0].split(',') {
+                    let sent = if target == ALL_CHANNEL {
+                        self.run("SNDALL", &[("msg", msg)])
+                    } else if target.starts_with(ROOM_MARK) {
+                        self.run("SENDROOM", &[("room", target), ("msg", msg)])
+                    } else {
+                        let login = login_of(target).unwrap_or_else(|| target.to_string());
+                        self.run("SEND", &[("username", &login), ("msg", msg)])
+                    };
+                    // notices never get automatic replies
+                    match sent {
+                        Err(e) if cmd == "PRIVMSG" => self.fail(target, e),
+                        _ => (),
+                    }
+                }
+            }
+            "JOIN" => {
+                for channel in params[0].split(',') {
+                    match self.join(channel) {
+                        Ok(()) => self.joined(channel),
+                        Err(e) => self.fail(channel, e),
+                    }
+                }
+            }
+            "PART" => {
+                for channel in params[0].split(',') {
+                    let parted = if channel == ALL_CHANNEL {
+                        Ok(String::new())
+                    } else {
+                        self.run("PART", &[("room", channel)])
+                    };
+                    match parted {
+                        Ok(_) => self.echo("PART", channel),
+                        Err(e) => self.fail(channel, e),
+                    }
+                }
+            }
+            "NAMES" => {
+                for channel in params[0].split(',') {
+                    self.names(channel);
+                }
+            }
+            "LIST" => {
+                let mut rooms = ClientDB::get_rooms();
+                rooms.sort();
+                for (room, members) in rooms {
+                    self.numeric("322", &format!("{}{} {} :", ROOM_MARK, room, members));
+                }
+                self.numeric("323", ":End of /LIST");
+            }
+            // modes and WHO aren't supported, but clients ask anyway
+            "MODE" if params[0].starts_with(ROOM_MARK) => {
+                self.numeric("324", &format!("{} +", params[0]))
+            }
+            "MODE" => self.numeric("221", "+"),
+            "WHO" => {
+                let mask = params.first().copied().unwrap_or("*");
+                self.numeric("315", &format!("{} :End of WHO list", mask));
+            }
+            _ => self.numeric("421", &format!("{} :Unknown command", cmd)),
+        }
+    }
+
+    /// Already being in the room is fine, the membership outlives connections
+    fn join(&mut self, channel: &str) -> RResult<()> {
+        if channel == ALL_CHANNEL {
+            return Ok(());
+        }
+        match self.run("JOIN", &[("room", channel)]) {
+            Ok(_) | Err(SError::AlreadyInRoom) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn joined(&mut self, channel: &str) {
+        self.echo("JOIN", channel);
+        self.names(channel);
+    }
+
+    /// Members of the channel who are online
+    fn names(&mut self, channel: &str) {
+        let nicks = if channel == ALL_CHANNEL {
+            ClientDB::get_online_logins()
+        } else {
+            let room = channel.strip_prefix(ROOM_MARK).unwrap_or(channel);
+            ClientDB::get_room_members(room)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|m| m.strip_suffix(&format!(" {}", ONLINE)))
+                .map(str::to_string)
+                .collect()
+        };
+        let nicks = nicks.iter().map(|n| as_nick(n)).collect::<Vec<String>>();
+        for chunk in nicks.chunks(NAMES_PER_LINE) {
+            self.numeric("353", &format!("= {} :{}", channel, chunk.join(" ")));
+        }
+        self.numeric("366", &format!("{} :End of /NAMES list", channel));
+    }
+
+    /// Log in once both NICK and USER have come
+    fn register(&mut self) {
+        let nick = match self.state.nick.clone() {
+            Some(nick) if self.state.user => nick,
+            _ => return,
+        };
+        let pass = match self.state.pass.clone() {
+            Some(pass) => pass,
+            None => {
+                self.numeric("464", ":Password required, set it as the server password");
+                return self.quit();
+            }
+        };
+        // a failed login closes the link, or clients would go on trying
+        // alternative nicks, and that would register new accounts
+        match self.run("LOGIN", &[("username", &nick), ("password", &pass)]) {
+            Ok(_) => (),
+            Err(SError::InvalidLogin) => {
+                self.numeric("432", &format!("{} :Erroneous nickname", nick));
+                return self.quit();
+            }
+            Err(SError::AlreadyLoggedIn) => {
+                self.numeric("433", &format!("{} :Nickname is already in use", nick));
+                return self.quit();
+            }
+            Err(SError::Banned(reason)) => {
+                self.numeric("465", &format!(":You are banned: {}", one_line(&reason)));
+                return self.quit();
+            }
+            Err(e) => {
+                self.numeric("464", &format!(":{}", one_line(&e.to_string())));
+                return self.quit();
+            }
+        }
+        self.state.registered = true;
+        let version = env!("CARGO_PKG_VERSION");
+        self.numeric("001", &format!(":Welcome to the lab chat, {}", nick));
+        self.numeric(
+            "002",
+            &format!(":Your host is {}, running version {}", SERVER_NAME, version),
+        );
+        self.numeric("003", ":This server has been running for a while");
+        self.numeric("004", &format!("{} {} o o", SERVER_NAME, version));
+        self.numeric(
+            "005",
+            "CHANTYPES=# NICKLEN=20 CHANNELLEN=33 :are supported by this server",
+        );
+        match settings().motd.as_deref() {
+            Some(motd) => {
+                self.numeric("375", &format!(":- {} Message of the day -", SERVER_NAME));
+                for line in motd.lines() {
+                    self.numeric("372", &format!(":- {}", line));
+                }
+                self.numeric("376", ":End of /MOTD command");
+            }
+            None => self.numeric("422", ":MOTD File is missing"),
+        }
+        self.joined(ALL_CHANNEL);
+        for room in ClientDB::get_user_rooms(self.uid) {
+            self.joined(&format!("{}{}", ROOM_MARK, room));
+        }
+    }
+}
+
+/// Run an IRC line from client `uid`, returns the lines to send back
+pub fn handle(line: &str, uid: Uuid, addr: &SocketAddr, session: &mut Session) -> Vec<String> {
+    let (cmd, params) = match parse(line) {
+        Some(parsed) => parsed,
+        None => return vec![],
+    };
+    if !["PASS", "PING", "PONG"].contains(&cmd.as_str()) {
+        info!("IRC from {}: {}", addr, line);
+    }
+    let state = session.irc.take().unwrap_or_default();
+    let mut conn = Conn {
+        uid,
+        addr,
+        session,
+        state,
+        out: vec![],
+    };
+    conn.handle(&cmd, &params);
+    conn.session.irc = Some(conn.state);
+    conn.out
+}
+
+/// IRC form of a push, None if the client shouldn't see it
+pub fn encode_push(session: &Session, push: &Push) -> Option<String> {
+    let me = session
+        .irc
+        .as_ref()
+        .filter(|s| s.registered)
+        .and_then(|s| s.nick.as_deref());
+    match push {
+        Push::Msg {
+            scope, from, msg, ..
+        } => {
+            let me = me?;
+            // clients show their own messages to channels by themselves
+            match scope {
+                Scope::Direct => Some(privmsg(from, me, msg)),
+                _ if from == me => None,
+                Scope::All => Some(privmsg(from, ALL_CHANNEL, msg)),
+                Scope::Room(room) => Some(privmsg(from, &format!("{}{}", ROOM_MARK, room), msg)),
+            }
+        }
+        Push::Timeout => Some("ERROR :Closing link: ping timeout".to_string()),
+        Push::Countdown { seconds, reason } => Some(format!(
+            ":{} NOTICE {} :Server shuts down in {} s{}",
+            SERVER_NAME,
+            me.unwrap_or("*"),
+            seconds,
+            reason
+                .as_ref()
+                .map(|r| format!(": {}", one_line(r)))
+                .unwrap_or_default()
+        )),
+        Push::Shutdown => Some("ERROR :Closing link".to_string()),
+    }
+}
+
+/// Error not tied to a command, such as a line too long
+pub fn notice(session: &Session, e: &SError) -> String {
+    let nick = session
+        .irc
+        .as_ref()
+        .and_then(|s| s.nick.as_deref())
+        .unwrap_or("*");
+    format!(
+        ":{} NOTICE {} :{}",
+        SERVER_NAME,
+        nick,
+        one_line(&e.to_string())
+    )
+}
+
+/// What a refused IRC connection is told before it's dropped
+pub fn refusal(e: &SError) -> String {
+    format!("ERROR :Closing link: {}\r\n", one_line(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::CliTask;
+
+    fn say(uid: Uuid, session: &mut Session, line: &str) -> String {
+        let addr = "127.0.0.1:6667".parse().unwrap();
+        handle(line, uid, &addr, session).join("\n")
+    }
+
+    fn registered(nick: &str) -> (Uuid, Session) {
+        let uid = ClientDB::add_client("127.0.0.1:6667".parse().unwrap());
+        let mut session = Session::irc();
+        assert_eq!(say(uid, &mut session, "PASS pw"), "");
+        assert_eq!(say(uid, &mut session, &format!("NICK {}", nick)), "");
+        let welcome = say(uid, &mut session, "USER u 0 * :Real Name");
+        assert!(welcome.contains(&format!(" 001 {} ", nick)));
+        assert!(welcome.contains(&format!(":{}!{}@pi_server JOIN #all", nick, nick)));
+        (uid, session)
+    }
+
+    #[test]
+    fn test_parse() {
+        let (cmd, params) = parse("@time=x :nick!u@h privmsg #lab :hi there :)").unwrap();
+        assert_eq!(cmd, "PRIVMSG");
+        assert_eq!(params, ["#lab", "hi there :)"]);
+        assert_eq!(parse("PING").unwrap().1.len(), 0);
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn test_session() {
+        ClientDB::init_test_db();
+        let anon = ClientDB::add_client("127.0.0.1:6667".parse().unwrap());
+        let mut session = Session::irc();
+        assert!(say(anon, &mut session, "JOIN #x").contains(" 451 "));
+        assert!(say(anon, &mut session, "PING :abc").ends_with("PONG pi_server :abc"));
+        assert!(say(anon, &mut session, "NICK :two words").contains(" 432 "));
+        assert!(say(anon, &mut session, "NICK #chan").contains(" 432 "));
+
+        let (alice, mut alice_session) = registered("irc_alice");
+        let (bob, mut bob_session) = registered("irc_bob");
+        assert!(say(alice, &mut alice_session, "NICK other").contains(" 484 "));
+        let joined = say(alice, &mut alice_session, "JOIN #irc_lab");
+        assert!(joined.contains("JOIN #irc_lab"));
+        assert!(joined.contains(" 353 irc_alice = #irc_lab :irc_alice"));
+        say(bob, &mut bob_session, "JOIN #irc_lab");
+
+        say(alice, &mut alice_session, "PRIVMSG #irc_lab :hello lab");
+        let pushes = ClientDB::get_all_client_jobs(bob)
+            .unwrap()
+            .into_iter()
+            .filter_map(|job| match job {
+                CliTask::RoomMsg(date, room, from, msg) => Some(Push::Msg {
+                    date,
+                    scope: Scope::Room(room),
+                    from,
+                    msg,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<Push>>();
+        assert_eq!(
+            encode_push(&bob_session, &pushes[0]).unwrap(),
+            ":irc_alice!irc_alice@pi_server PRIVMSG #irc_lab :hello lab"
+        );
+        // the sender's own copy isn't echoed back
+        assert!(encode_push(&alice_session, &pushes[0]).is_none());
+
+        let direct = Push::Msg {
+            date: String::new(),
+            scope: Scope::Direct,
+            from: "irc_bob".to_string(),
+            msg: "two\nlines\rQUIT".to_string(),
+        };
+        assert_eq!(
+            encode_push(&alice_session, &direct).unwrap(),
+            ":irc_bob!irc_bob@pi_server PRIVMSG irc_alice :two\r\n\
+             :irc_bob!irc_bob@pi_server PRIVMSG irc_alice :lines\r\n\
+             :irc_bob!irc_bob@pi_server PRIVMSG irc_alice :QUIT"
+        );
+        // a login of another protocol with a space, which isn't an SNDALL
+        let spaced = Push::Msg {
+            date: String::new(),
+            scope: Scope::Direct,
+            from: "bob (to all)".to_string(),
+            msg: "hi".to_string(),
+        };
+        assert_eq!(
+            encode_push(&alice_session, &spaced).unwrap(),
+            ":bob_(to_all)!bob_(to_all)@pi_server PRIVMSG irc_alice :hi"
+        );
+        assert!(say(alice, &mut alice_session, "PRIVMSG nobody_here :hi").contains(" 401 "));
+
+        // replies reach the login behind a sanitized nick
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let carol = ClientDB::add_client(addr);
+        ClientDB::set_login(carol, &addr, "irc carol".into(), "pw".into()).unwrap();
+        assert_eq!(say(alice, &mut alice_session, "PRIVMSG irc_carol :hi"), "");
+        assert!(matches!(
+            &ClientDB::get_all_client_jobs(carol).unwrap()[..],
+            [CliTask::SendMsg(_, from, msg)] if from == "irc_alice" && msg == "hi"
+        ));
+
+        // no line is longer than IRC allows, and nothing is lost
+        let long = Push::Msg {
+            date: String::new(),
+            scope: Scope::Direct,
+            from: "irc_bob".to_string(),
+            msg: "é".repeat(600),
+        };
+        let encoded = encode_push(&alice_session, &long).unwrap();
+        let lines = encoded.split("\r\n").collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.len() + 2 <= MAX_LINE));
+        let head = ":irc_bob!irc_bob@pi_server PRIVMSG irc_alice :";
+        let text = lines.iter().map(|l| &l[head.len()..]).collect::<String>();
+        assert_eq!(text, "é".repeat(600));
+        assert!(say(alice, &mut alice_session, "PART #irc_nowhere").contains(" 442 "));
+        assert!(say(alice, &mut alice_session, "KNOCK #irc_lab").contains(" 421 "));
+    }
+}
@@ -0,0 +1,96 @@
+use std::str::FromStr;
+
+use crate::error::SError;
+
+/// Language of error messages on a connection, switched with `LANG|lang=`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Lang {
+    #[default]
+    En,
+    Ru,
+}
+
+impl FromStr for Lang {
+    type Err = SError;
+
+    fn from_str(s: &str) -> Result<Lang, SError> {
+        match s.to_lowercase().as_str() {
+            "en" => Ok(Lang::En),
+            "ru" => Ok(Lang::Ru),
+            _ => Err(SError::UnknownLang),
+        }
+    }
+}
+
+impl Lang {
+    /// Message of an error, English ones are the `Display` of `SError`
+    pub fn describe(self, e: &SError) -> String {
+        match self {
+            Lang::En => e.to_string(),
+            Lang::Ru => ru(e),
+        }
+    }
+}
+
+fn ru(e: &SError) -> String {
+    match e {
+        SError::AlreadyLoggedIn => "Вы уже вошли".to_string(),
+        SError::DOS(ms) => format!("Слишком часто, повторите через {} мс", ms),
+        SError::TooManyConnections(n) => {
+            format!("Слишком много соединений с вашего адреса, максимум {}", n)
+        }
+        SError::LoginAlreadyExists => "Такой логин уже есть".to_string(),
+        SError::InvalidLogin => "Неверный логин: только печатные символы ascii и русские буквы, \
+                                 без ':', не длиннее 20 символов"
+            .to_string(),
+        SError::NotLoggedIn => "Сначала войдите (LOGIN)".to_string(),
+        SError::PermissionDenied(role) => format!("Недостаточно прав: нужна роль {}", role),
+        SError::Untouchable => {
+            "Нельзя модерировать себя и пользователей с такой же или более высокой ролью"
+                .to_string()
+        }
+        SError::NotOnline => "Пользователь не в сети".to_string(),
+        SError::QueueFull(n) => {
+            format!("Очередь сообщений пользователя заполнена: максимум {}", n)
+        }
+        SError::Muted(minutes) => format!("Вам запрещено писать ещё {} мин", minutes),
+        SError::Banned(reason) => format!("Бан: {}", reason),
+        SError::NotBanned => "Бана нет".to_string(),
+        SError::NoSuchUser => "Нет такого пользователя".to_string(),
+        SError::UnknownCommand => "Неизвестная команда".to_string(),
+        SError::WrongArgs(args) => format!("Нужные аргументы: {}", args),
+        SError::WrongPassword => "Неверный пароль".to_string(),
+        SError::InvalidToken => "Нет API-токена или он неверный".to_string(),
+        SError::SyntaxError(why) => format!("Синтаксическая ошибка: {}", why),
+        SError::LineTooLong(n) => format!("Слишком длинная строка: максимум {} байт", n),
+        SError::UnknownProto => "Неизвестный протокол, есть text и json".to_string(),
+        SError::UnknownLang => "Неизвестный язык, есть en и ru".to_string(),
+        SError::InvalidRoom => "Неверное имя комнаты: латинские буквы, цифры, '_' и '-', \
+                                не длиннее 32 символов"
+            .to_string(),
+        SError::NoSuchRoom => "Нет такой комнаты".to_string(),
+        SError::NotInRoom => "Вы не в этой комнате".to_string(),
+        SError::AlreadyInRoom => "Вы уже в этой комнате".to_string(),
+        SError::TooManyRooms(n) => format!("Слишком много комнат: максимум {}", n),
+        SError::InvalidArg(why) => format!("Неверный аргумент: {}", why),
+        SError::Reload(why) => format!("Конфиг не перезагружен: {}", why),
+        SError::Internal => "Внутренняя ошибка сервера".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!("RU".parse::<Lang>().unwrap(), Lang::Ru);
+        assert!(matches!("de".parse::<Lang>(), Err(SError::UnknownLang)));
+        let e = SError::DOS(120);
+        assert_eq!(Lang::En.describe(&e), "Too fast, retry after 120 ms");
+        assert_eq!(
+            Lang::Ru.describe(&e),
+            "Слишком часто, повторите через 120 мс"
+        );
+    }
+}
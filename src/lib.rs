@@ -0,0 +1,30 @@
+#![allow(unused_must_use)]
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod api;
+pub mod auth;
+pub mod chat_client;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod framing;
+pub mod history;
+pub mod http;
+pub mod irc;
+pub mod lang;
+pub mod protocol;
+pub mod ratelimit;
+pub mod server;
+pub mod storage;
+pub mod transport;
+pub mod utils;
+
+pub use chat_client::{ChatClient, Event, User};
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate log;
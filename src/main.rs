@@ -1,30 +1,21 @@
-#![allow(unused_must_use)]
-use std::env;
 use std::fs::OpenOptions;
-use std::net::TcpListener;
 use std::panic;
 use std::process;
 use std::thread;
+use std::time::Duration;
 
-mod api;
-mod client;
-mod config;
-mod db;
-mod error;
-mod protocol;
-mod utils;
-
-use client::Client;
-use config::*;
-use db::ClientDB;
-use utils::daemonize;
-
-#[macro_use]
-extern crate lazy_static;
+use pi_server::{
+    api,
+    config::{set_cli, set_settings, settings, Cli, Settings},
+    db::ClientDB,
+    server::{self, Server},
+    storage, transport,
+    utils::daemonize,
+};
 
+use clap::Parser;
 #[macro_use]
 extern crate log;
-extern crate simplelog;
 use signal_hook::{iterator::Signals, SIGHUP, SIGINT, SIGTERM};
 use simplelog::*;
 
@@ -36,8 +27,11 @@ fn init_logger(show_stderr: bool) {
     let logfile = OpenOptions::new()
         .append(true)
         .create(true)
-        .open(LOGFILE)
-        .unwrap();
+        .open(&settings().log)
+        .unwrap_or_else(|e| {
+            eprintln!("Can't open log file {}: {}", settings().log, e);
+            process::exit(1)
+        });
     let mut loggers: Vec<Box<dyn simplelog::SharedLogger>> = vec![WriteLogger::new(
         LevelFilter::Debug,
         log_cfg.clone(),
@@ -51,26 +45,44 @@ fn init_logger(show_stderr: bool) {
         ))
     }
     CombinedLogger::init(loggers).unwrap();
+    log::set_max_level(settings().log_filter());
 }
 
 fn init_statics() {
-    ClientDB::_lock_read();
+    let cfg = settings();
+    let loaded = storage::open(&cfg.storage, cfg.db_path(), &cfg.history, &cfg.bans)
+        .map_err(|e| e.to_string())
+        .and_then(ClientDB::load);
+    if let Err(e) = loaded {
+        error!("Can't load db: {}", e);
+        process::exit(1);
+    }
+    ClientDB::start_autosave();
 }
 
 fn init_sighandlers() {
-    let signals = Signals::new(&[SIGINT, SIGTERM, SIGHUP]).unwrap();
+    let signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
     thread::spawn(move || {
+        let mut stopping = false;
         for sig in signals.forever() {
             match sig {
-                SIGINT | SIGTERM => {
+                SIGINT | SIGTERM if !stopping => {
                     info!("Gracefully stopping...");
+                    stopping = true;
+                    server::stop(Duration::ZERO, None);
+                }
+                // a second signal means not to wait for the clients
+                SIGINT | SIGTERM => {
+                    info!("Stopping right away");
                     info!("Syncing db");
                     ClientDB::sync_db();
                     info!("Done");
                     process::exit(0);
                 }
                 SIGHUP => {
-                    info!("SIGHUP received");
+                    info!("SIGHUP received, reloading config");
+                    // an invalid config is logged and changes nothing
+                    api::reload().ok();
                     info!("Syncing db");
                     ClientDB::sync_db();
                     info!("Done");
@@ -86,33 +98,72 @@ fn set_panic_hook() {
 }
 
 fn listen() {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", PORT)).unwrap();
-    info!("Listening on port {}", PORT);
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let addr = match stream.peer_addr() {
-                    Ok(a) => a,
-                    Err(_) => continue,
-                };
-                info!("New connection: {}", &addr);
-                thread::spawn(move || Client::handle(stream, addr));
-            }
-            Err(e) => {
-                error!("Error: {}", e);
-            }
-        }
+    let addr = (settings().bind, settings().port).into();
+    let mut server = Server::bind(addr).unwrap_or_else(|e| {
+        error!("Can't listen on {}: {}", addr, e);
+        process::exit(1)
+    });
+    info!("Listening on {}", addr);
+    let cfg = settings();
+    if let (Some(port), Some(cert), Some(key)) = (cfg.tls_port, &cfg.tls_cert, &cfg.tls_key) {
+        let tls_addr = (cfg.bind, port).into();
+        let tls_cfg = transport::tls_config(cert, key).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1)
+        });
+        server.listen_tls(tls_addr, tls_cfg).unwrap_or_else(|e| {
+            error!("Can't listen on {}: {}", tls_addr, e);
+            process::exit(1)
+        });
+        info!("Listening for TLS on {}", tls_addr);
+    }
+    if let Some(port) = cfg.ws_port {
+        let ws_addr = (cfg.bind, port).into();
+        server.listen_ws(ws_addr).unwrap_or_else(|e| {
+            error!("Can't listen on {}: {}", ws_addr, e);
+            process::exit(1)
+        });
+        info!("Listening for WebSocket on {}", ws_addr);
+    }
+    if let Some(port) = cfg.http_port {
+        let http_addr = (cfg.bind, port).into();
+        server.listen_http(http_addr).unwrap_or_else(|e| {
+            error!("Can't listen on {}: {}", http_addr, e);
+            process::exit(1)
+        });
+        info!("HTTP API on {}", http_addr);
     }
+    if let Some(port) = cfg.irc_port {
+        let irc_addr = (cfg.bind, port).into();
+        server.listen_irc(irc_addr).unwrap_or_else(|e| {
+            error!("Can't listen on {}: {}", irc_addr, e);
+            process::exit(1)
+        });
+        info!("Listening for IRC on {}", irc_addr);
+    }
+    server.run();
+    info!("Syncing db");
+    ClientDB::sync_db();
+    info!("Done");
 }
 
 fn main() {
-    let mut is_daemon = false;
-    if let Some(arg) = env::args().nth(1) {
-        if arg == "-d" {
-            if let Ok(pid) = daemonize() {
-                is_daemon = true;
-                debug!("Forked to background (pid {})", pid);
-            }
+    let cli = Cli::parse();
+    let cfg = Settings::load(&cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2)
+    });
+    if cli.print_config {
+        print!("{}", cfg.to_toml());
+        return;
+    }
+    let mut is_daemon = cfg.daemon;
+    set_settings(cfg);
+    set_cli(cli);
+    if is_daemon {
+        match daemonize() {
+            Ok(pid) => debug!("Forked to background (pid {})", pid),
+            Err(_) => is_daemon = false,
         }
     }
     init_logger(!is_daemon);
@@ -1,7 +1,7 @@
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_while},
-    combinator::eof,
+    bytes::complete::{escaped_transform, is_not, tag, take_while},
+    combinator::{all_consuming, eof, value},
     error::VerboseError,
     multi::separated_list0,
     sequence::separated_pair,
@@ -12,21 +12,66 @@ use std::collections::HashMap;
 use crate::api::{Args, Command};
 
 const SEP: &str = "|";
+const ESC: char = '\\';
 
 type Data = str;
 type IVerbResult<Left, Parsed> = IResult<Left, Parsed, VerboseError<Left>>;
 
-fn is_alpha(c: char) -> bool {
-    let chr = c as u8;
-    (chr >= 0x41 && chr <= 0x5A) || (chr >= 0x61 && chr <= 0x7A)
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
 }
 
-fn parse_args(s: &Data) -> IVerbResult<&Data, Vec<(&Data, &Data)>> {
-    let arg_line = separated_pair(take_while(is_alpha), tag("="), is_not(SEP));
-    separated_list0(tag(SEP), arg_line)(s)
+/// Argument value with `\\`, `\|`, `\n`, `\r` and `\#` escapes resolved
+fn parse_value(s: &Data) -> IVerbResult<&Data, String> {
+    escaped_transform(
+        is_not("\\|"),
+        ESC,
+        alt((
+            value("\\", tag("\\")),
+            value("|", tag("|")),
+            value("\n", tag("n")),
+            value("\r", tag("r")),
+            // only produced for replies, see `Proto::encode_reply`
+            value("#", tag("#")),
+        )),
+    )(s)
 }
 
-pub fn parse_request(s: &Data) -> IVerbResult<&Data, Command> {
+/// The whole rest of the line, so that a bad escape or a stray `|` is an
+/// error rather than the end of the arguments
+fn parse_args(s: &Data) -> IVerbResult<&Data, Vec<(&Data, String)>> {
+    let arg_line = separated_pair(take_while(is_key_char), tag("="), parse_value);
+    all_consuming(separated_list0(tag(SEP), arg_line))(s)
+}
+
+/// Inverse of the value escaping done by the request parser, so that any
+/// string can be safely put into an argument or a single response line
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '|' => escaped.push_str("\\|"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape`, for values coming back in responses and pushes
+pub fn unescape(s: &str) -> Option<String> {
+    if s.is_empty() {
+        return Some(String::new());
+    }
+    match parse_value(s) {
+        Ok(("", value)) => Some(value),
+        _ => None,
+    }
+}
+
+pub fn parse_request(s: &Data) -> IVerbResult<&Data, Command<'_>> {
     let (s, cmd) = is_not(SEP)(s)?;
     let (s, separator) = alt((tag(SEP), eof))(s)?;
     let (s, args) = if separator == SEP {
@@ -37,11 +82,14 @@ pub fn parse_request(s: &Data) -> IVerbResult<&Data, Command> {
     let args: Args = {
         let mut _args = HashMap::new();
         args.into_iter().for_each(|(k, v)| {
-            _args.insert(k.trim(), v);
+            _args.insert(k.trim().into(), v);
         });
         _args
     };
-    let command = Command { cmd, args };
+    let command = Command {
+        cmd: cmd.into(),
+        args,
+    };
     Ok((s, command))
 }
 
@@ -52,15 +100,43 @@ mod tests {
     fn test_command_parse() {
         let cmd = "SENDALL|MSG=qwe|TO=asde zxc";
         let expected = Command {
-            cmd: "SENDALL",
+            cmd: "SENDALL".into(),
             args: {
-                let mut args: HashMap<&str, &str> = HashMap::new();
-                args.insert("MSG", "qwe");
-                args.insert("TO", "asde zxc");
+                let mut args: Args = HashMap::new();
+                args.insert("MSG".into(), "qwe".to_string());
+                args.insert("TO".into(), "asde zxc".to_string());
                 args
             },
         };
         let (_, result) = parse_request(cmd).unwrap();
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn test_escaped_args() {
+        let cmd = r"SEND|username=a\\b|msg=ls \| grep x\nok|to_2=x=y";
+        let (_, result) = parse_request(cmd).unwrap();
+        assert_eq!(result.args["username"], r"a\b");
+        assert_eq!(result.args["msg"], "ls | grep x\nok");
+        assert_eq!(result.args["to_2"], "x=y");
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        let msg = "cat a|wc -l\r\n\\n |";
+        let cmd = format!("ECHO|msg={}", escape(msg));
+        assert!(!cmd.contains('\n'));
+        let (_, result) = parse_request(&cmd).unwrap();
+        assert_eq!(result.args["msg"], msg);
+        assert_eq!(unescape(&escape(msg)).as_deref(), Some(msg));
+    }
+
+    #[test]
+    fn test_bad_args() {
+        let (_, result) = parse_request(r"SEND|msg=C:\\temp|username=foo").unwrap();
+        assert_eq!(result.args["msg"], r"C:\temp");
+        // an unknown escape used to silently drop the args after it
+        assert!(parse_request(r"SEND|msg=C:\temp|username=foo").is_err());
+        assert!(parse_request("SEND|username=foo|msg").is_err());
+    }
 }
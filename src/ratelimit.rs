@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::config::settings;
+
+/// Buckets are dropped once there are more of them and they are full again
+const PRUNE_ABOVE: usize = 1024;
+
+/// Commands not listed in the settings cost one token
+pub const DEFAULT_COST: f64 = 1.0;
+
+/// Built-in costs, the `[costs]` table of the config overrides them
+pub const COSTS: &[(&str, f64)] = &[
+    ("PING", 0.2),
+    ("HELP", 0.5),
+    ("LOGIN", 3.0),
+    ("SNDALL", 5.0),
+    ("SENDROOM", 2.0),
+    ("HISTORY", 2.0),
+];
+
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn full(burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+
+    /// Take `cost` tokens, or tell how long to wait until there are enough
+    pub fn take(&mut self, cost: f64, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, burst, now);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((cost - self.tokens) / rate))
+        }
+    }
+
+    fn is_full(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.tokens >= burst
+    }
+}
+
+/// Who pays for a command: an account, so that reconnecting doesn't help,
+/// or the connection itself before login
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub enum Payer {
+    User(String),
+    Conn(Uuid),
+}
+
+#[derive(Default)]
+struct Limiter {
+    commands: HashMap<Payer, TokenBucket>,
+    connects: HashMap<IpAddr, TokenBucket>,
+}
+
+lazy_static! {
+    static ref LIMITER: Mutex<Limiter> = Mutex::new(Limiter::default());
+}
+
+fn take<K: std::hash::Hash + Eq>(
+    buckets: &mut HashMap<K, TokenBucket>,
+    key: K,
+    cost: f64,
+    rate: f64,
+    burst: f64,
+) -> Result<(), Duration> {
+    let now = Instant::now();
+    if buckets.len() > PRUNE_ABOVE {
+        buckets.retain(|_, b| !b.is_full(rate, burst, now));
+    }
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::full(burst, now))
+        .take(cost, rate, burst, now)
+}
+
+/// Charge `payer` for running `cmd`
+pub fn check_command(payer: Payer, cmd: &str) -> Result<(), Duration> {
+    let cfg = settings();
+    let cost = cfg.costs.get(cmd).copied().unwrap_or(DEFAULT_COST);
+    let mut limiter = LIMITER.lock().unwrap();
+    take(&mut limiter.commands, payer, cost, cfg.rate, cfg.burst)
+}
+
+/// Charge `ip` for opening a new connection
+pub fn check_connect(ip: IpAddr) -> Result<(), Duration> {
+    let cfg = settings();
+    let mut limiter = LIMITER.lock().unwrap();
+    take(
+        &mut limiter.connects,
+        ip,
+        1.0,
+        cfg.conn_rate,
+        cfg.conn_burst,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(2.0, start);
+        // a burst passes, then the rate kicks in
+        assert!(bucket.take(1.0, 4.0, 2.0, start).is_ok());
+        assert!(bucket.take(0.5, 4.0, 2.0, start).is_ok());
+        let wait = bucket.take(1.0, 4.0, 2.0, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(125));
+        assert!(bucket.take(1.0, 4.0, 2.0, start + wait).is_ok());
+        // never more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.is_full(4.0, 2.0, later));
+        assert!(bucket.take(2.0, 4.0, 2.0, later).is_ok());
+        assert!(bucket.take(0.1, 4.0, 2.0, later).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+use rustls::ServerConfig;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::{
+    api::retry_after, client::Client, codec::Proto, config::settings, db::ClientDB, error::SError,
+    ratelimit,
+    client::{self, Session, Slow},
+    http::{self, HttpApi},
+    irc,
+    lang::Lang,
+    transport::{TlsStream, Transport, WsStream},
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const TLS_LISTENER: Token = Token(2);
+const WS_LISTENER: Token = Token(3);
+const IRC_LISTENER: Token = Token(4);
+const FIRST_CLIENT: usize = 5;
+const EVENTS_CAPACITY: usize = 1024;
+/// How long clients get to take their `SHUTDOWN` when the server stops
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Shutdown requested by a signal or an admin
+struct Stop {
+    at: Instant,
+    reason: Option<String>,
+}
+
+/// A slow command that has finished, with the session it was run in and
+/// its replies, None if it panicked
+type Resumed = (Uuid, Session, Option<Vec<String>>);
+
+#[derive(Default)]
+struct Wakeups {
+    waker: Option<Arc<Waker>>,
+    jobs: HashSet<Uuid>,
+    exits: HashSet<Uuid>,
+    resumed: Vec<Resumed>,
+    stop: Option<Stop>,
+}
+
+lazy_static! {
+    static ref WAKEUPS: Mutex<Wakeups> = Mutex::new(Wakeups::default());
+    // a single worker, so that password hashing can't eat all the memory
+    static ref WORKER: Mutex<mpsc::Sender<(Uuid, Session, Slow)>> = {
+        let (tx, rx) = mpsc::channel::<(Uuid, Session, Slow)>();
+        thread::spawn(move || {
+            for (uid, mut session, job) in rx {
+                let replies = panic::catch_unwind(AssertUnwindSafe(|| job(&mut session)));
+                let mut w = WAKEUPS.lock().unwrap();
+                w.resumed.push((uid, session, replies.ok()));
+                wake(&w);
+            }
+        });
+        Mutex::new(tx)
+    };
+}
+
+fn wake(w: &Wakeups) {
+    if let Some(waker) = w.waker.as_ref() {
+        if let Err(e) = waker.wake() {
+            error!("Can't wake reactor: {}", e);
+        }
+    }
+}
+
+/// Tell the reactor that client `uid` has new jobs queued
+pub fn notify(uid: Uuid) {
+    let mut w = WAKEUPS.lock().unwrap();
+    w.jobs.insert(uid);
+    wake(&w);
+}
+
+/// Same as `notify` for a whole batch of clients
+pub fn notify_many<I: IntoIterator<Item = Uuid>>(uids: I) {
+    let mut w = WAKEUPS.lock().unwrap();
+    w.jobs.extend(uids);
+    wake(&w);
+}
+
+/// Make the reactor send `SHUTDOWN` to client `uid` and close its socket
+pub fn disconnect(uid: Uuid) {
+    let mut w = WAKEUPS.lock().unwrap();
+    w.exits.insert(uid);
+    wake(&w);
+}
+
+/// Run `job` of client `uid` off the reactor thread. The client gets its
+/// session back along with the replies through `Client::resume`.
+pub fn run_slow(uid: Uuid, session: Session, job: Slow) {
+    if WORKER.lock().unwrap().send((uid, session, job)).is_err() {
+        error!("The worker thread is gone");
+    }
+}
+
+/// Make the reactor stop in `delay`. Clients are warned right away if
+/// there is a delay or a reason, a later call replaces the earlier one.
+pub fn stop(delay: Duration, reason: Option<String>) {
+    let mut w = WAKEUPS.lock().unwrap();
+    w.stop = Some(Stop {
+        at: Instant::now() + delay,
+        reason,
+    });
+    wake(&w);
+}
+
+/// What is spoken on a listening port
+enum Kind {
+    Plain,
+    Tls(Arc<ServerConfig>),
+    WebSocket,
+    Irc,
+}
+
+impl Kind {
+    fn wrap(&self, stream: TcpStream) -> Result<Box<dyn Transport>, String> {
+        Ok(match self {
+            Kind::Plain | Kind::Irc => Box::new(stream),
+            Kind::Tls(config) => {
+                Box::new(TlsStream::new(stream, config.clone()).map_err(|e| e.to_string())?)
+            }
+            Kind::WebSocket => Box::new(
+                WsStream::new(Box::new(stream), settings().read_buf_size)
+                    .map_err(|e| e.to_string())?,
+            ),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Plain => "",
+            Kind::Tls(_) => "TLS ",
+            Kind::WebSocket => "WebSocket ",
+            Kind::Irc => "IRC ",
+        }
+    }
+
+    fn session(&self) -> Session {
+        match self {
+            Kind::Irc => Session::irc(),
+            _ => Session::default(),
+        }
+    }
+
+    /// Why a connection is refused, in a form its peer understands
+    fn refusal(&self, e: SError) -> Option<String> {
+        match self {
+            Kind::Plain => Some(Proto::Text.encode_reply(Lang::En, None, &Err(e)) + "\n"),
+            Kind::Irc => Some(irc::refusal(&e)),
+            // these can't be answered before their handshake
+            Kind::Tls(_) | Kind::WebSocket => None,
+        }
+    }
+}
+
+pub struct Server {
+    poll: Poll,
+    listeners: HashMap<Token, (TcpListener, Kind)>,
+    clients: HashMap<Token, Client>,
+    tokens: HashMap<Uuid, Token>,
+    conns_per_ip: HashMap<IpAddr, usize>,
+    next_token: usize,
+    stop_at: Option<Instant>,
+    /// Set once the listeners are closed, the moment to give up on clients
+    drain_until: Option<Instant>,
+    http: Option<HttpApi>,
+}
+
+impl Server {
+    pub fn bind(addr: SocketAddr) -> io::Result<Server> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        WAKEUPS.lock().unwrap().waker = Some(Arc::new(waker));
+        let mut server = Server {
+            poll,
+            listeners: HashMap::new(),
+            clients: HashMap::new(),
+            tokens: HashMap::new(),
+            conns_per_ip: HashMap::new(),
+            next_token: FIRST_CLIENT,
+            stop_at: None,
+            drain_until: None,
+            http: None,
+        };
+        server.add_listener(LISTENER, addr, Kind::Plain)?;
+        Ok(server)
+    }
+
+    fn add_listener(&mut self, token: Token, addr: SocketAddr, kind: Kind) -> io::Result<()> {
+        let mut listener = TcpListener::bind(addr)?;
+        self.poll
+            .registry()
+            .register(&mut listener, token, Interest::READABLE)?;
+        self.listeners.insert(token, (listener, kind));
+        Ok(())
+    }
+
+    /// Also accept TLS connections on `addr`
+    pub fn listen_tls(&mut self, addr: SocketAddr, config: Arc<ServerConfig>) -> io::Result<()> {
+        self.add_listener(TLS_LISTENER, addr, Kind::Tls(config))
+    }
+
+    /// Also accept WebSocket connections on `addr`
+    pub fn listen_ws(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.add_listener(WS_LISTENER, addr, Kind::WebSocket)
+    }
+
+    /// Also accept IRC clients on `addr`
+    pub fn listen_irc(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.add_listener(IRC_LISTENER, addr, Kind::Irc)
+    }
+
+    /// Also serve the REST API on `addr`, it stops along with the rest
+    pub fn listen_http(&mut self, addr: SocketAddr) -> io::Result<()> {
+        self.http = Some(http::start(addr)?);
+        Ok(())
+    }
+
+    /// Serve until a requested stop is over
+    pub fn run(&mut self) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        loop {
+            let timeout = self
+                .clients
+                .values()
+                .map(Client::deadline)
+                .chain(self.stop_at)
+                .chain(self.drain_until)
+                .min()
+                .map(|d| d.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Poll failed: {}", e);
+                break;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => (),
+                    token if self.listeners.contains_key(&token) => self.accept(token),
+                    token => {
+                        if let Some(client) = self.clients.get_mut(&token) {
+                            if event.is_writable() {
+                                client.flush();
+                            }
+                            if event.is_readable() {
+                                client.on_readable();
+                            }
+                        }
+                        // commands may have queued jobs for anyone, including
+                        // clients further down in this batch of events
+                        self.dispatch_wakeups();
+                    }
+                }
+            }
+            self.dispatch_wakeups();
+            if self.stop_at.is_some_and(|at| at <= Instant::now()) {
+                self.drain();
+            }
+            self.expire_silent();
+            self.reap();
+            if let Some(until) = self.drain_until {
+                if self.clients.is_empty() || until <= Instant::now() {
+                    break;
+                }
+            }
+        }
+        if !self.clients.is_empty() {
+            info!("Dropping {} clients that didn't take SHUTDOWN", self.clients.len());
+        }
+        // dropping the clients marks them offline before the last sync
+        self.clients.clear();
+        self.tokens.clear();
+        // and HTTP requests still running may change the db too
+        if let Some(http) = self.http.take() {
+            http.join(self.drain_until.unwrap_or_else(Instant::now));
+        }
+    }
+
+    /// Stop accepting and send everyone `SHUTDOWN`, the loop ends once
+    /// the sockets are flushed or `DRAIN_TIMEOUT` passes
+    fn drain(&mut self) {
+        self.stop_at = None;
+        for (_, (mut listener, _)) in self.listeners.drain() {
+            self.poll.registry().deregister(&mut listener).ok();
+        }
+        if let Some(http) = self.http.as_mut() {
+            http.unblock();
+        }
+        info!("Stopped listening, closing {} connections", self.clients.len());
+        self.drain_until = Some(Instant::now() + DRAIN_TIMEOUT);
+        self.clients.values_mut().for_each(Client::exit);
+    }
+
+    fn accept(&mut self, listener_token: Token) {
+        loop {
+            let (listener, kind) = &self.listeners[&listener_token];
+            match listener.accept() {
+                Ok((mut stream, addr)) => {
+                    if let Err(e) = self.admit(addr.ip()) {
+                        info!("Refused {}: {}", addr, e);
+                        // best effort, the socket is dropped right away anyway
+                        if let Some(reply) = kind.refusal(e) {
+                            let _ = stream.write_all(reply.as_bytes());
+                        }
+                        continue;
+                    }
+                    let token = Token(self.next_token);
+                    if let Err(e) = self.poll.registry().register(
+                        &mut stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        error!("Can't register {}: {}", addr, e);
+                        continue;
+                    }
+                    info!("New {}connection: {}", kind.name(), &addr);
+                    let conn = match kind.wrap(stream) {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            error!("Can't set up {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+                    self.next_token += 1;
+                    let client = Client::new(conn, addr, kind.session());
+                    *self.conns_per_ip.entry(addr.ip()).or_default() += 1;
+                    self.tokens.insert(client.uid(), token);
+                    self.clients.insert(token, client);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Whether a new connection from `ip` may be served
+    fn admit(&self, ip: IpAddr) -> Result<(), SError> {
+        if let Some(ban) = ClientDB::get_ip_ban(ip) {
+            return Err(SError::Banned(ban.reason));
+        }
+        let max_conns = settings().max_conns_per_ip;
+        if self.conns_per_ip.get(&ip).copied().unwrap_or(0) >= max_conns {
+            return Err(SError::TooManyConnections(max_conns));
+        }
+        ratelimit::check_connect(ip).map_err(|wait| SError::DOS(retry_after(wait)))
+    }
+
+    fn dispatch_wakeups(&mut self) {
+        let (resumed, jobs, exits, stop) = {
+            let mut w = WAKEUPS.lock().unwrap();
+            if w.resumed.is_empty() && w.jobs.is_empty() && w.exits.is_empty() && w.stop.is_none()
+            {
+                return;
+            }
+            (
+                w.resumed.drain(..).collect::<Vec<_>>(),
+                w.jobs.drain().collect::<Vec<_>>(),
+                w.exits.drain().collect::<Vec<_>>(),
+                w.stop.take(),
+            )
+        };
+        for (uid, session, replies) in resumed {
+            match self.client_by_uid(uid) {
+                Some(client) => client.resume(session, replies),
+                // it left while its LOGIN ran, which may have set it online
+                None => client::release(uid),
+            }
+        }
+        for uid in jobs {
+            if let Some(client) = self.client_by_uid(uid) {
+                client.apply_jobs();
+            }
+        }
+        for uid in exits {
+            if let Some(client) = self.client_by_uid(uid) {
+                client.exit();
+            }
+        }
+        if let Some(stop) = stop {
+            self.schedule_stop(stop);
+        }
+    }
+
+    fn schedule_stop(&mut self, stop: Stop) {
+        if self.drain_until.is_some() {
+            return;
+        }
+        let left = stop.at.saturating_duration_since(Instant::now());
+        let seconds = left.as_secs_f64().round() as u64;
+        info!(
+            "Stopping in {} s{}",
+            seconds,
+            stop.reason
+                .as_ref()
+                .map(|r| format!(": {}", r))
+                .unwrap_or_default()
+        );
+        if seconds > 0 || stop.reason.is_some() {
+            for client in self.clients.values_mut() {
+                client.countdown(seconds, stop.reason.clone());
+            }
+        }
+        self.stop_at = Some(stop.at);
+    }
+
+    fn client_by_uid(&mut self, uid: Uuid) -> Option<&mut Client> {
+        let token = self.tokens.get(&uid)?;
+        self.clients.get_mut(token)
+    }
+
+    fn expire_silent(&mut self) {
+        let now = Instant::now();
+        self.clients
+            .values_mut()
+            .filter(|c| c.deadline() <= now)
+            .for_each(Client::timeout);
+    }
+
+    fn reap(&mut self) {
+        let closed = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.is_closed())
+            .map(|(t, _)| *t)
+            .collect::<Vec<Token>>();
+        for token in closed {
+            if let Some(client) = self.clients.remove(&token) {
+                self.tokens.remove(&client.uid());
+                let ip = client.addr().ip();
+                if let Some(n) = self.conns_per_ip.get_mut(&ip) {
+                    *n -= 1;
+                    if *n == 0 {
+                        self.conns_per_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+}
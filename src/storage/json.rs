@@ -0,0 +1,373 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Storage, StorageResult};
+use crate::{
+    db::{CliData, IpBan},
+    error::StorageError,
+    history::{HistoryQuery, Message},
+};
+
+/// The original `users.json`: an array of users with their jobs inlined.
+/// Messages go to a separate file, one json object per line, and are
+/// also kept in memory once read, the file itself is written by a thread
+/// of its own. Banned addresses are one more array.
+pub struct JsonStorage {
+    path: PathBuf,
+    backup: PathBuf,
+    history_path: PathBuf,
+    history: Mutex<Option<History>>,
+    writer: Mutex<mpsc::Sender<HistoryWrite>>,
+    bans_path: PathBuf,
+}
+
+struct History {
+    messages: Vec<Message>,
+    /// Id of the last appended message, never goes down
+    last_id: u64,
+}
+
+/// The first line of a rewritten history file, for when the dropped
+/// messages were the newest ones, so their ids aren't given out again
+#[derive(Serialize, Deserialize)]
+struct LastId {
+    last_id: u64,
+}
+
+enum HistoryWrite {
+    Append(Vec<u8>),
+    Replace(Vec<u8>),
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+fn writer_gone() -> StorageError {
+    io::Error::new(io::ErrorKind::BrokenPipe, "history writer is gone").into()
+}
+
+/// Applies the writes in order, with one fsync per batch of appends rather
+/// than one per message. Errors are logged and reported to the next flush.
+fn write_history(path: PathBuf, writes: mpsc::Receiver<HistoryWrite>) {
+    let mut file: Option<File> = None;
+    let mut failed: Option<io::Error> = None;
+    while let Ok(first) = writes.recv() {
+        let mut next = Some(first);
+        let mut dirty = false;
+        let mut waiting = vec![];
+        while let Some(write) = next {
+            let written = match write {
+                HistoryWrite::Append(line) => {
+                    dirty = true;
+                    let opened = match file.take() {
+                        Some(f) => Ok(f),
+                        None => OpenOptions::new().create(true).append(true).open(&path),
+                    };
+                    opened.and_then(|f| file.insert(f).write_all(&line))
+                }
+                HistoryWrite::Replace(data) => {
+                    // the open file is the one being replaced
+                    file = None;
+                    write_atomic(&path, &backup_of(&path), &data)
+                }
+                HistoryWrite::Flush(done) => {
+                    waiting.push(done);
+                    Ok(())
+                }
+            };
+            if let Err(e) = written {
+                error!("Failed to write history to {}: {}", path.display(), e);
+                failed.get_or_insert(e);
+            }
+            next = writes.try_recv().ok();
+        }
+        if dirty {
+            if let Some(Err(e)) = file.as_ref().map(File::sync_data) {
+                error!("Failed to write history to {}: {}", path.display(), e);
+                failed.get_or_insert(e);
+            }
+        }
+        for done in waiting {
+            done.send(failed.take().map_or(Ok(()), Err)).ok();
+        }
+    }
+}
+
+fn backup_of(path: &Path) -> PathBuf {
+    let mut backup = path.to_path_buf().into_os_string();
+    backup.push(".bak");
+    backup.into()
+}
+
+impl JsonStorage {
+    pub fn new<P, H, B>(path: P, history_path: H, bans_path: B) -> JsonStorage
+    where
+        P: AsRef<Path>,
+        H: AsRef<Path>,
+        B: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let history_path = history_path.as_ref().to_path_buf();
+        let (writer, writes) = mpsc::channel();
+        let writer_path = history_path.clone();
+        thread::spawn(move || write_history(writer_path, writes));
+        JsonStorage {
+            backup: backup_of(&path),
+            path,
+            history_path,
+            history: Mutex::new(None),
+            writer: Mutex::new(writer),
+            bans_path: bans_path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Missing or empty file is an empty array, anything unparsable is an error
+    fn read_array<T: serde::de::DeserializeOwned>(path: &Path) -> StorageResult<Vec<T>> {
+        let data = match fs::read(path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        if data.iter().all(u8::is_ascii_whitespace) {
+            return Ok(vec![]);
+        }
+        serde_json::from_slice(&data).map_err(|e| {
+            StorageError::Corrupted(format!(
+                "{}: {}; fix it or restore the previous version from {}",
+                path.display(),
+                e,
+                backup_of(path).display()
+            ))
+        })
+    }
+
+    fn read_history(&self) -> StorageResult<History> {
+        let mut history = History {
+            messages: vec![],
+            last_id: 0,
+        };
+        let file = match File::open(&self.history_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e.into()),
+        };
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Message>(&line) {
+                Ok(msg) => {
+                    history.last_id = history.last_id.max(msg.id);
+                    history.messages.push(msg);
+                }
+                // the last line may be cut short by a crash, that's fine
+                Err(e) if e.is_eof() => warn!("Skipping truncated history record"),
+                Err(e) => match serde_json::from_str::<LastId>(&line) {
+                    Ok(last) if n == 0 => history.last_id = history.last_id.max(last.last_id),
+                    _ => {
+                        return Err(StorageError::Corrupted(format!(
+                            "{}:{}: {}",
+                            self.history_path.display(),
+                            n + 1,
+                            e
+                        )))
+                    }
+                },
+            }
+        }
+        Ok(history)
+    }
+
+    fn with_history<T, F>(&self, f: F) -> StorageResult<T>
+    where
+        F: FnOnce(&mut History) -> StorageResult<T>,
+    {
+        let mut history = self.history.lock().unwrap();
+        if history.is_none() {
+            *history = Some(self.read_history()?);
+        }
+        f(history.as_mut().unwrap())
+    }
+
+    fn write(&self, write: HistoryWrite) -> StorageResult<()> {
+        let sent = self.writer.lock().unwrap().send(write);
+        sent.map_err(|_| writer_gone())
+    }
+}
+
+/// Replace `path` with `data` so that a crash at any moment leaves either
+/// the old or the new version on disk. The old one is kept in `backup`.
+fn write_atomic(path: &Path, backup: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    if path.exists() {
+        fs::remove_file(backup).ok();
+        if fs::hard_link(path, backup).is_err() {
+            fs::copy(path, backup)?;
+        }
+    }
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+impl Storage for JsonStorage {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn load(&self) -> StorageResult<Vec<CliData>> {
+        Self::read_array(&self.path)
+    }
+
+    fn save(&self, users: &[CliData]) -> StorageResult<()> {
+        let data = serde_json::to_vec(users)?;
+        write_atomic(&self.path, &self.backup, &data)?;
+        Ok(())
+    }
+
+    fn append_message(&self, mut msg: Message) -> StorageResult<Message> {
+        self.with_history(|history| {
+            msg.id = history.last_id + 1;
+            let mut line = serde_json::to_vec(&msg)?;
+            line.push(b'\n');
+            self.write(HistoryWrite::Append(line))?;
+            history.last_id = msg.id;
+            history.messages.push(msg.clone());
+            Ok(msg)
+        })
+    }
+
+    fn history(&self, query: &HistoryQuery) -> StorageResult<Vec<Message>> {
+        self.with_history(|history| Ok(query.select(history.messages.iter())))
+    }
+
+    fn forget_user(&self, login: &str) -> StorageResult<usize> {
+        self.with_history(|history| {
+            let before = history.messages.len();
+            history.messages.retain(|m| !m.is_dm_of(login));
+            let dropped = before - history.messages.len();
+            if dropped > 0 {
+                let last_id = LastId {
+                    last_id: history.last_id,
+                };
+                let mut data = serde_json::to_vec(&last_id)?;
+                data.push(b'\n');
+                for msg in history.messages.iter() {
+                    serde_json::to_writer(&mut data, msg)?;
+                    data.push(b'\n');
+                }
+                self.write(HistoryWrite::Replace(data))?;
+            }
+            Ok(dropped)
+        })
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        let (done, flushed) = mpsc::channel();
+        self.write(HistoryWrite::Flush(done))?;
+        match flushed.recv() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(writer_gone()),
+        }
+    }
+
+    fn load_ip_bans(&self) -> StorageResult<Vec<IpBan>> {
+        Self::read_array(&self.bans_path)
+    }
+
+    fn save_ip_bans(&self, bans: &[IpBan]) -> StorageResult<()> {
+        let data = serde_json::to_vec(bans)?;
+        write_atomic(&self.bans_path, &backup_of(&self.bans_path), &data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Ban, CliData};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_atomic_write_and_read() {
+        let dir = std::env::temp_dir().join(format!("pi_server_db_{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("users.json");
+        let bans_path = dir.join("bans.json");
+        let storage = JsonStorage::new(&path, dir.join("history.jsonl"), &bans_path);
+        assert!(storage.load().unwrap().is_empty());
+
+        storage.save(&[CliData::named("foo")]).unwrap();
+        assert!(!storage.backup.exists());
+        storage.save(&[]).unwrap();
+        assert!(storage.load().unwrap().is_empty());
+        let previous = JsonStorage::new(&storage.backup, "", "").load().unwrap();
+        assert_eq!(previous[0].login(), Some("foo"));
+
+        fs::write(&path, b"[{\"addr\":").unwrap();
+        assert!(storage.load().is_err());
+
+        assert!(storage.load_ip_bans().unwrap().is_empty());
+        let bans = [IpBan {
+            addr: "10.0.0.1".parse().unwrap(),
+            ban: Ban::new("mod".into(), "spam".into()),
+        }];
+        storage.save_ip_bans(&bans).unwrap();
+        assert_eq!(storage.load_ip_bans().unwrap(), bans);
+        fs::write(&bans_path, b"{").unwrap();
+        assert!(storage.load_ip_bans().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("pi_server_db_{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let history_path = dir.join("history.jsonl");
+        let storage = JsonStorage::new(dir.join("users.json"), &history_path, "");
+        for to in &[None, Some("b")] {
+            let msg = Message::new("a".into(), to.map(str::to_string), "hi".into());
+            storage.append_message(msg).unwrap();
+        }
+        storage.flush().unwrap();
+        // a crash in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&history_path).unwrap();
+        file.write_all(b"{\"id\":3,\"da").unwrap();
+
+        let reopened = JsonStorage::new(dir.join("users.json"), &history_path, "");
+        let query = HistoryQuery {
+            user: "b".into(),
+            with: None,
+            before: None,
+            limit: 10,
+        };
+        let ids = reopened
+            .history(&query)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2]);
+
+        assert_eq!(reopened.forget_user("b").unwrap(), 1);
+        reopened.flush().unwrap();
+        let reopened = JsonStorage::new(dir.join("users.json"), &history_path, "");
+        assert!(reopened.history(&query).unwrap().iter().all(|m| m.id == 1));
+        assert_eq!(reopened.history(&query).unwrap().len(), 1);
+        // the id of the dropped message isn't given out again
+        let msg = Message::new("a".into(), None, "hi".into());
+        assert_eq!(reopened.append_message(msg).unwrap().id, 3);
+        reopened.flush().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
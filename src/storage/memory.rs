@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+
+use super::{Storage, StorageResult};
+use crate::{
+    db::{CliData, IpBan},
+    history::{HistoryQuery, Message},
+};
+
+/// Keeps the "persisted" users and messages in memory, for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: Mutex<Vec<CliData>>,
+    messages: Mutex<Vec<Message>>,
+    /// Id of the last appended message, never goes down
+    last_id: Mutex<u64>,
+    ip_bans: Mutex<Vec<IpBan>>,
+}
+
+impl Storage for MemoryStorage {
+    fn describe(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn load(&self) -> StorageResult<Vec<CliData>> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    fn save(&self, users: &[CliData]) -> StorageResult<()> {
+        *self.users.lock().unwrap() = users.to_vec();
+        Ok(())
+    }
+
+    fn append_message(&self, mut msg: Message) -> StorageResult<Message> {
+        let mut messages = self.messages.lock().unwrap();
+        let mut last_id = self.last_id.lock().unwrap();
+        *last_id += 1;
+        msg.id = *last_id;
+        messages.push(msg.clone());
+        Ok(msg)
+    }
+
+    fn history(&self, query: &HistoryQuery) -> StorageResult<Vec<Message>> {
+        Ok(query.select(self.messages.lock().unwrap().iter()))
+    }
+
+    fn forget_user(&self, login: &str) -> StorageResult<usize> {
+        let mut messages = self.messages.lock().unwrap();
+        let before = messages.len();
+        messages.retain(|m| !m.is_dm_of(login));
+        Ok(before - messages.len())
+    }
+
+    fn load_ip_bans(&self) -> StorageResult<Vec<IpBan>> {
+        Ok(self.ip_bans.lock().unwrap().clone())
+    }
+
+    fn save_ip_bans(&self, bans: &[IpBan]) -> StorageResult<()> {
+        *self.ip_bans.lock().unwrap() = bans.to_vec();
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+mod json;
+mod memory;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::{
+    db::{CliData, IpBan},
+    error::StorageError,
+    history::{HistoryQuery, Message},
+};
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// Where `ClientDB` keeps registered users and the jobs queued for them
+/// between restarts, and the history of delivered messages. The users db
+/// itself lives in memory, a backend only loads it on startup and receives
+/// full snapshots to persist. Messages are appended one by one.
+pub trait Storage: Send + Sync {
+    /// Human-readable location for logs and error messages
+    fn describe(&self) -> String;
+
+    /// Every stored user with their pending jobs
+    fn load(&self) -> StorageResult<Vec<CliData>>;
+
+    /// Replace everything stored with `users`
+    fn save(&self, users: &[CliData]) -> StorageResult<()>;
+
+    /// Store a delivered message, returning it with its new id. It may
+    /// reach the disk a bit later, see `flush`.
+    fn append_message(&self, msg: Message) -> StorageResult<Message>;
+
+    /// Messages selected by `query`, oldest first
+    fn history(&self, query: &HistoryQuery) -> StorageResult<Vec<Message>>;
+
+    /// Drop the direct messages from and to `login`, whoever registers the
+    /// name next mustn't read them. Returns how many were dropped.
+    fn forget_user(&self, login: &str) -> StorageResult<usize>;
+
+    /// Wait until everything appended so far is on disk
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Every banned address
+    fn load_ip_bans(&self) -> StorageResult<Vec<IpBan>>;
+
+    /// Replace all stored address bans with `bans`
+    fn save_ip_bans(&self, bans: &[IpBan]) -> StorageResult<()>;
+}
+
+/// Open the backend named `kind` ("json" or "sqlite") at `path`. The json
+/// backend keeps the history and banned addresses in separate files.
+pub fn open(
+    kind: &str,
+    path: &str,
+    history_path: &str,
+    bans_path: &str,
+) -> StorageResult<Box<dyn Storage>> {
+    match kind {
+        "json" => Ok(Box::new(JsonStorage::new(path, history_path, bans_path))),
+        "sqlite" => Ok(Box::new(SqliteStorage::open(path)?)),
+        "memory" => Ok(Box::new(MemoryStorage::default())),
+        _ => Err(StorageError::UnknownBackend(kind.to_string())),
+    }
+}
@@ -0,0 +1,357 @@
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use super::{Storage, StorageResult};
+use crate::{
+    db::{CliData, IpBan},
+    error::StorageError,
+    history::{HistoryQuery, Message},
+};
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS users (
+        uid TEXT PRIMARY KEY,
+        login TEXT NOT NULL UNIQUE,
+        record TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        uid TEXT NOT NULL REFERENCES users(uid) ON DELETE CASCADE,
+        task TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS ip_bans (
+        addr TEXT PRIMARY KEY,
+        record TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        date TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        receiver TEXT,
+        text TEXT NOT NULL
+    );
+";
+
+/// Users and their jobs in an embedded SQLite database. The user record
+/// itself is kept as json, so new fields don't need schema migrations.
+/// Messages get their ids right away and are inserted by a thread of its
+/// own, which waits for the connection while a snapshot is being saved.
+pub struct SqliteStorage {
+    path: String,
+    conn: Arc<Mutex<Connection>>,
+    /// Id of the last appended message, never goes down
+    last_id: Mutex<u64>,
+    writer: Mutex<mpsc::Sender<MessageWrite>>,
+}
+
+enum MessageWrite {
+    Insert(Message),
+    Flush(mpsc::Sender<StorageResult<()>>),
+}
+
+fn writer_gone() -> StorageError {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "history writer is gone").into()
+}
+
+/// Inserts the messages, one transaction per batch. Errors are logged and
+/// reported to the next flush.
+fn write_messages(conn: Arc<Mutex<Connection>>, writes: mpsc::Receiver<MessageWrite>) {
+    let mut failed: Option<StorageError> = None;
+    while let Ok(first) = writes.recv() {
+        let mut next = Some(first);
+        let mut batch = vec![];
+        let mut waiting = vec![];
+        while let Some(write) = next {
+            match write {
+                MessageWrite::Insert(msg) => batch.push(msg),
+                MessageWrite::Flush(done) => waiting.push(done),
+            }
+            next = writes.try_recv().ok();
+        }
+        if !batch.is_empty() {
+            if let Err(e) = insert_messages(&mut conn.lock().unwrap(), &batch) {
+                error!("Failed to save {} messages: {}", batch.len(), e);
+                failed.get_or_insert(e);
+            }
+        }
+        for done in waiting {
+            done.send(failed.take().map_or(Ok(()), Err)).ok();
+        }
+    }
+}
+
+fn insert_messages(conn: &mut Connection, messages: &[Message]) -> StorageResult<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO messages (id, date, sender, receiver, text)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for msg in messages {
+            insert.execute(params![msg.id as i64, msg.date, msg.from, msg.to, msg.text])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> StorageResult<SqliteStorage> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        // unlike MAX(id), remembers the ids of deleted messages
+        let last_id: i64 = conn.query_row(
+            "SELECT COALESCE(MAX(seq), 0) FROM sqlite_sequence WHERE name = 'messages'",
+            [],
+            |r| r.get(0),
+        )?;
+        let conn = Arc::new(Mutex::new(conn));
+        let (writer, writes) = mpsc::channel();
+        let writer_conn = conn.clone();
+        thread::spawn(move || write_messages(writer_conn, writes));
+        Ok(SqliteStorage {
+            path: path.to_string(),
+            conn,
+            last_id: Mutex::new(last_id as u64),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn write(&self, write: MessageWrite) -> StorageResult<()> {
+        let sent = self.writer.lock().unwrap().send(write);
+        sent.map_err(|_| writer_gone())
+    }
+}
+
+fn corrupted(uid: &str, e: impl ToString) -> StorageError {
+    StorageError::Corrupted(format!("user {}: {}", uid, e.to_string()))
+}
+
+impl Storage for SqliteStorage {
+    fn describe(&self) -> String {
+        format!("sqlite:{}", self.path)
+    }
+
+    fn load(&self) -> StorageResult<Vec<CliData>> {
+        let conn = self.conn.lock().unwrap();
+        let mut jobs: HashMap<String, Vec<Value>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT uid, task FROM jobs ORDER BY id")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (uid, task) = row?;
+            let task = serde_json::from_str(&task).map_err(|e| corrupted(&uid, e))?;
+            jobs.entry(uid).or_default().push(task);
+        }
+        let mut stmt = conn.prepare("SELECT uid, record FROM users ORDER BY rowid")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        let mut users = vec![];
+        for row in rows {
+            let (uid, record) = row?;
+            let mut record: Value = serde_json::from_str(&record).map_err(|e| corrupted(&uid, e))?;
+            let user_jobs = jobs.remove(&uid).unwrap_or_default();
+            match record.as_object_mut() {
+                Some(r) => r.insert("jobs".to_string(), Value::Array(user_jobs)),
+                None => return Err(corrupted(&uid, "record is not an object")),
+            };
+            users.push(serde_json::from_value(record).map_err(|e| corrupted(&uid, e))?);
+        }
+        Ok(users)
+    }
+
+    fn save(&self, users: &[CliData]) -> StorageResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM users", [])?;
+        {
+            let mut insert_user =
+                tx.prepare("INSERT INTO users (uid, login, record) VALUES (?1, ?2, ?3)")?;
+            let mut insert_job = tx.prepare("INSERT INTO jobs (uid, task) VALUES (?1, ?2)")?;
+            for user in users {
+                let uid = user.uid().to_string();
+                let mut record = serde_json::to_value(user)?;
+                let jobs = record
+                    .as_object_mut()
+                    .and_then(|r| r.remove("jobs"))
+                    .unwrap_or(Value::Null);
+                insert_user.execute(params![uid, user.login(), record.to_string()])?;
+                for task in jobs.as_array().into_iter().flatten() {
+                    insert_job.execute(params![uid, task.to_string()])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn append_message(&self, mut msg: Message) -> StorageResult<Message> {
+        // the lock keeps the inserts in the order of ids
+        let mut last_id = self.last_id.lock().unwrap();
+        msg.id = *last_id + 1;
+        self.write(MessageWrite::Insert(msg.clone()))?;
+        *last_id = msg.id;
+        Ok(msg)
+    }
+
+    fn history(&self, query: &HistoryQuery) -> StorageResult<Vec<Message>> {
+        // including the messages still on their way to the database
+        self.flush()?;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, date, sender, receiver, text FROM messages
+             WHERE id < ?1 AND CASE WHEN ?3 IS NULL
+                 THEN receiver IS NULL OR sender = ?2 OR receiver = ?2
+                 ELSE (sender = ?2 AND receiver = ?3) OR (sender = ?3 AND receiver = ?2)
+             END
+             ORDER BY id DESC LIMIT ?4",
+        )?;
+        let before = query.before.map(|b| b as i64).unwrap_or(i64::MAX);
+        let rows = stmt.query_map(
+            params![before, query.user, query.with, query.limit as i64],
+            |r| {
+                Ok(Message {
+                    id: r.get::<_, i64>(0)? as u64,
+                    date: r.get(1)?,
+                    from: r.get(2)?,
+                    to: r.get(3)?,
+                    text: r.get(4)?,
+                })
+            },
+        )?;
+        let mut messages = rows.collect::<Result<Vec<_>, _>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn forget_user(&self, login: &str) -> StorageResult<usize> {
+        self.flush()?;
+        let conn = self.conn.lock().unwrap();
+        let dropped = conn.execute(
+            "DELETE FROM messages
+             WHERE receiver IS NOT NULL AND (sender = ?1 OR receiver = ?1)",
+            params![login],
+        )?;
+        Ok(dropped)
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        let (done, flushed) = mpsc::channel();
+        self.write(MessageWrite::Flush(done))?;
+        flushed.recv().map_err(|_| writer_gone())?
+    }
+
+    fn load_ip_bans(&self) -> StorageResult<Vec<IpBan>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT addr, record FROM ip_bans ORDER BY rowid")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+        let mut bans = vec![];
+        for row in rows {
+            let (addr, record) = row?;
+            let corrupted = |e: &dyn ToString| {
+                StorageError::Corrupted(format!("ban of {}: {}", addr, e.to_string()))
+            };
+            bans.push(serde_json::from_str(&record).map_err(|e| corrupted(&e))?);
+        }
+        Ok(bans)
+    }
+
+    fn save_ip_bans(&self, bans: &[IpBan]) -> StorageResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM ip_bans", [])?;
+        {
+            let mut insert = tx.prepare("INSERT INTO ip_bans (addr, record) VALUES (?1, ?2)")?;
+            for ban in bans {
+                let record = serde_json::to_string(ban)?;
+                insert.execute(params![ban.addr.to_string(), record])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::CliTask;
+    use crate::db::Ban;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_roundtrip() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut user = CliData::named("foo");
+        user.push_job(CliTask::SendMsg("now".into(), "bar".into(), "hi".into()));
+        user.push_job(CliTask::Exit);
+        storage.save(&[user, CliData::named("bar")]).unwrap();
+        storage.save(&[storage.load().unwrap().remove(0)]).unwrap();
+
+        let users = storage.load().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].login(), Some("foo"));
+        assert!(matches!(users[0].jobs()[0], CliTask::SendMsg(_, _, _)));
+        assert!(matches!(users[0].jobs()[1], CliTask::Exit));
+
+        let bans = [IpBan {
+            addr: "::1".parse().unwrap(),
+            ban: Ban::new("mod".into(), "spam".into()),
+        }];
+        storage.save_ip_bans(&bans).unwrap();
+        storage.save_ip_bans(&bans).unwrap();
+        assert_eq!(storage.load_ip_bans().unwrap(), bans);
+    }
+
+    #[test]
+    fn test_history() {
+        let path = std::env::temp_dir().join(format!("pi_server_db_{}.sqlite", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let storage = SqliteStorage::open(path).unwrap();
+        for (from, to) in &[
+            ("a", None),
+            ("a", Some("b")),
+            ("c", Some("a")),
+            ("b", Some("a")),
+        ] {
+            let msg = Message::new(from.to_string(), to.map(str::to_string), "hi".into());
+            storage.append_message(msg).unwrap();
+        }
+        let ids = |with: Option<&str>, before| {
+            let query = HistoryQuery {
+                user: "b".into(),
+                with: with.map(str::to_string),
+                before,
+                limit: 10,
+            };
+            storage
+                .history(&query)
+                .unwrap()
+                .iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(None, None), vec![1, 2, 4]);
+        assert_eq!(ids(None, Some(4)), vec![1, 2]);
+        assert_eq!(ids(Some("a"), None), vec![2, 4]);
+
+        // ids of the dropped messages aren't given out again
+        assert_eq!(storage.forget_user("b").unwrap(), 2);
+        let msg = Message::new("a".into(), None, "hi".into());
+        assert_eq!(storage.append_message(msg).unwrap().id, 5);
+        assert_eq!(ids(None, None), vec![1, 5]);
+        let msg = Message::new("a".into(), Some("c".into()), "hi".into());
+        storage.append_message(msg).unwrap();
+        assert_eq!(storage.forget_user("c").unwrap(), 2);
+        drop(storage);
+        let reopened = SqliteStorage::open(path).unwrap();
+        let msg = Message::new("a".into(), None, "hi".into());
+        assert_eq!(reopened.append_message(msg).unwrap().id, 7);
+        for suffix in &["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path, suffix)).ok();
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use mio::net::TcpStream;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection,
+};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::sync::Arc;
+use tungstenite::{
+    handshake::{server::NoCallback, HandshakeError, MidHandshake},
+    protocol::WebSocketConfig,
+    Error as WsError, Message, ServerHandshake, WebSocket,
+};
+
+use crate::error::ConfigError;
+
+/// Byte stream a `Client` talks over. Both kinds are nonblocking:
+/// `WouldBlock` means "come back on the next poll event".
+pub trait Transport: Read + Write + Send {
+    /// Say goodbye to the peer and close both directions
+    fn shutdown(&mut self);
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&mut self) {
+        TcpStream::shutdown(self, Shutdown::Both).ok();
+    }
+}
+
+pub struct TlsStream {
+    sock: TcpStream,
+    conn: ServerConnection,
+}
+
+impl TlsStream {
+    pub fn new(sock: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream, rustls::Error> {
+        Ok(TlsStream {
+            sock,
+            conn: ServerConnection::new(config)?,
+        })
+    }
+
+    /// Push out the records rustls has ready, as far as the socket takes them
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // no plaintext yet, go get more records
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                // let the peer know why, if we can
+                self.write_tls().ok();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            // handshake messages have to go out before any data comes in
+            self.write_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.writer().write(buf)?;
+        self.write_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()
+    }
+}
+
+impl Transport for TlsStream {
+    fn shutdown(&mut self) {
+        self.conn.send_close_notify();
+        self.write_tls().ok();
+        self.sock.shutdown(Shutdown::Both).ok();
+    }
+}
+
+enum WsState {
+    Handshake(MidHandshake<ServerHandshake<Box<dyn Transport>, NoCallback>>),
+    Open(WebSocket<Box<dyn Transport>>),
+    Closed,
+}
+
+/// WebSocket carrying the usual protocol, a command or a reply per text frame.
+/// The `Client` still sees lines: frames are read as lines and every line
+/// written goes out as a frame.
+pub struct WsStream {
+    state: WsState,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::ErrorKind::NotConnected.into(),
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Queued frames are sent later, when the socket is writable again
+fn ws_sent(res: Result<(), WsError>) -> io::Result<()> {
+    match res {
+        Err(WsError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        res => res.map_err(ws_error),
+    }
+}
+
+impl WsStream {
+    /// Start the server side handshake, frames over `max_message` bytes are refused
+    pub fn new(stream: Box<dyn Transport>, max_message: usize) -> io::Result<WsStream> {
+        let config = WebSocketConfig {
+            max_message_size: Some(max_message),
+            max_frame_size: Some(max_message),
+            ..WebSocketConfig::default()
+        };
+        let state = match tungstenite::accept_with_config(stream, Some(config)) {
+            Ok(ws) => WsState::Open(ws),
+            Err(HandshakeError::Interrupted(mid)) => WsState::Handshake(mid),
+            Err(HandshakeError::Failure(e)) => return Err(ws_error(e)),
+        };
+        Ok(WsStream {
+            state,
+            input: vec![],
+            output: vec![],
+        })
+    }
+
+    /// Move the handshake on, `WouldBlock` until it is done
+    fn handshake(&mut self) -> io::Result<()> {
+        match mem::replace(&mut self.state, WsState::Closed) {
+            WsState::Handshake(mid) => match mid.handshake() {
+                Ok(ws) => self.state = WsState::Open(ws),
+                Err(HandshakeError::Interrupted(mid)) => {
+                    self.state = WsState::Handshake(mid);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Err(HandshakeError::Failure(e)) => return Err(ws_error(e)),
+            },
+            state => self.state = state,
+        }
+        Ok(())
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.input.is_empty() {
+            self.handshake()?;
+            let ws = match &mut self.state {
+                WsState::Open(ws) => ws,
+                _ => return Ok(0),
+            };
+            match ws.read() {
+                Ok(Message::Text(text)) => self.input.extend(text.as_bytes()),
+                Ok(Message::Binary(data)) => self.input.extend(data),
+                // pings and closing are answered by tungstenite itself
+                Ok(_) => (),
+                Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => return Ok(0),
+                Err(e) => return Err(ws_error(e)),
+            }
+            // a frame is a whole command even without the newline
+            if !self.input.is_empty() && !self.input.ends_with(b"\n") {
+                self.input.push(b'\n');
+            }
+        }
+        let n = buf.len().min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        self.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.handshake() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            res => res?,
+        }
+        let ws = match &mut self.state {
+            WsState::Open(ws) => ws,
+            _ => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        while let Some(end) = self.output.iter().position(|b| *b == b'\n') {
+            let line = self.output.drain(..=end).collect::<Vec<u8>>();
+            let text = String::from_utf8_lossy(&line[..end]).into_owned();
+            ws_sent(ws.write(Message::Text(text)))?;
+        }
+        ws_sent(ws.flush())
+    }
+}
+
+impl Transport for WsStream {
+    fn shutdown(&mut self) {
+        if let WsState::Open(ws) = &mut self.state {
+            ws.close(None).ok();
+            ws.flush().ok();
+            ws.get_mut().shutdown();
+        }
+        self.state = WsState::Closed;
+    }
+}
+
+/// Server side TLS settings from PEM encoded certificate chain and key
+pub fn tls_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, ConfigError> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| ConfigError::Io(path.to_string(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|e| ConfigError::Io(cert_path.to_string(), e))?;
+    if certs.is_empty() {
+        return Err(ConfigError::Tls(format!(
+            "no certificates in {}",
+            cert_path
+        )));
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| ConfigError::Io(key_path.to_string(), e))?
+        .ok_or_else(|| ConfigError::Tls(format!("no private key in {}", key_path)))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|b| b.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| ConfigError::Tls(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryInto;
+    use std::fs;
+    use std::io::{BufRead, ErrorKind};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    /// Poll a nonblocking stream like the reactor would until a line comes in
+    fn read_line(stream: &mut dyn Read) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut buf = [0u8; 64];
+        let mut got = vec![];
+        while !got.ends_with(b"\n") {
+            assert!(Instant::now() < deadline, "handshake stuck");
+            match stream.read(&mut buf) {
+                Ok(n) => {
+                    assert!(n > 0);
+                    got.extend(&buf[..n]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        got
+    }
+
+    #[test]
+    fn test_tls_roundtrip() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("pi_server_tls_{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let cert_path = cert_path.to_str().unwrap();
+        assert!(tls_config(cert_path, cert_path).is_err());
+        let config = tls_config(cert_path, key_path.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let peer = thread::spawn(move || {
+            let client_config =
+                ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .unwrap()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+            let name: ServerName = "localhost".try_into().unwrap();
+            let conn = ClientConnection::new(Arc::new(client_config), name).unwrap();
+            let sock = std::net::TcpStream::connect(addr).unwrap();
+            let mut tls = io::BufReader::new(rustls::StreamOwned::new(conn, sock));
+            tls.get_mut().write_all(b"PING\n").unwrap();
+            let mut reply = String::new();
+            tls.read_line(&mut reply).unwrap();
+            reply
+        });
+
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let mut stream = TlsStream::new(TcpStream::from_std(sock), config).unwrap();
+        assert_eq!(read_line(&mut stream), b"PING\n");
+        stream.write_all(b"+PONG\n").unwrap();
+        stream.flush().unwrap();
+        assert_eq!(peer.join().unwrap(), "+PONG\n");
+        stream.shutdown();
+    }
+
+    #[test]
+    fn test_ws_roundtrip() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let sock = std::net::TcpStream::connect(addr).unwrap();
+            let (mut ws, _) = tungstenite::client(format!("ws://{}/", addr), sock).unwrap();
+            // the newline is optional, a frame is a command anyway
+            ws.send(Message::Text("PING".into())).unwrap();
+            ws.send(Message::Text("ECHO|msg=1\nECHO|msg=2\n".into()))
+                .unwrap();
+            let replies = (0..3)
+                .map(|_| ws.read().unwrap().into_text().unwrap())
+                .collect::<Vec<String>>();
+            ws.close(None).unwrap();
+            replies
+        });
+
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let mut stream = WsStream::new(Box::new(TcpStream::from_std(sock)), 64).unwrap();
+        assert_eq!(read_line(&mut stream), b"PING\n");
+        assert_eq!(read_line(&mut stream), b"ECHO|msg=1\nECHO|msg=2\n");
+        stream.write_all(b"+PONG\n+1\n").unwrap();
+        stream.write_all(b"+").unwrap();
+        stream.write_all(b"2\n").unwrap();
+        assert_eq!(peer.join().unwrap(), ["+PONG", "+1", "+2"]);
+        let mut buf = [0u8; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+}